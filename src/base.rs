@@ -0,0 +1,183 @@
+//! Creates the base entry and its missing ancestors.
+//!
+//! The object classes of these entries are derived from the attribute type of their RDN, e.g.
+//! `dc=example` becomes a `dcObject` and `organization`, `ou=people` an `organizationalUnit`.
+
+use std::collections::HashSet;
+
+use anyhow::Context;
+use ldap3::{LdapError, Scope};
+
+use crate::dn::split_dn;
+use crate::ldap_pool::LdapPool;
+use crate::insert::add;
+use crate::result_codes::NO_SUCH_OBJECT;
+use crate::retry::{with_retries, RetryPolicy};
+use crate::types::LdapEntry;
+
+/// Builds the entries of `base` and all of its ancestors, starting with the topmost one.
+pub fn base_entries(base: &str) -> anyhow::Result<Vec<LdapEntry>> {
+    let mut entries = vec![];
+    let mut dn = base.trim();
+
+    while !dn.is_empty() {
+        let (rdn, parent) = split_dn(dn);
+        entries.push(rdn_entry(dn, rdn)?);
+        dn = parent;
+    }
+
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Builds the entry `dn` using the default object classes of the type of `rdn`.
+fn rdn_entry(dn: &str, rdn: &str) -> anyhow::Result<LdapEntry> {
+    let Some((attribute, value)) = rdn.split_once('=') else {
+        bail!("invalid RDN {rdn:?} in {dn}")
+    };
+    if is_multi_valued(rdn) {
+        bail!("multi-valued RDN {rdn:?} in {dn} is not supported");
+    }
+
+    let attribute = attribute.trim();
+    let value = unescape_value(value.trim());
+    let classes: &[&str] = match attribute.to_ascii_lowercase().as_str() {
+        "dc" => &["dcObject", "organization"],
+        "ou" => &["organizationalUnit"],
+        "o" => &["organization"],
+        "c" => &["country"],
+        "l" => &["locality"],
+        _ => bail!("can't derive the object classes of {dn}, create it manually"),
+    };
+
+    let mut entry = vec![
+        ("objectClass".to_string(), ["top"].iter().chain(classes).map(|c| c.to_string()).collect()),
+        (attribute.to_string(), HashSet::from([value.clone()])),
+    ];
+    // organization requires the `o` attribute
+    if attribute.eq_ignore_ascii_case("dc") {
+        entry.push(("o".to_string(), HashSet::from([value])));
+    }
+
+    Ok((dn.to_string(), entry))
+}
+
+/// Whether `rdn` consists of multiple attributes joined by an unescaped `+`.
+fn is_multi_valued(rdn: &str) -> bool {
+    let mut escaped = false;
+    for c in rdn.chars() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '+' if !escaped => return true,
+            _ => escaped = false,
+        }
+    }
+
+    false
+}
+
+/// Removes the escaping of an RDN value, both `\,` and hex pairs like `\2C`.
+fn unescape_value(value: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+
+        let hex = rest.get(..2).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (hex, rest.split_first()) {
+            (Some(byte), _) => {
+                bytes.push(byte);
+                rest = &rest[2..];
+            }
+            (None, Some((&c, tail))) => {
+                bytes.push(c);
+                rest = tail;
+            }
+            (None, None) => bytes.push(b),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Adds the entries of `base` and its ancestors that don't exist yet, walking up until an
+/// existing entry is found. Returns the number of created entries.
+pub async fn create_base(pool: &LdapPool, base: &str, retry: &RetryPolicy) -> anyhow::Result<usize> {
+    let entries = base_entries(base)?;
+
+    let mut missing = 0;
+    for (dn, _) in entries.iter().rev() {
+        if entry_exists(pool, dn, retry).await? {
+            break;
+        }
+        missing += 1;
+    }
+
+    for (dn, attributes) in entries.iter().skip(entries.len() - missing) {
+        let (_, result) = with_retries(retry, || add(pool, dn, attributes)).await;
+        result.with_context(|| format!("failed to create {dn}"))?;
+        info!("created {dn}");
+    }
+
+    Ok(missing)
+}
+
+async fn entry_exists(pool: &LdapPool, dn: &str, retry: &RetryPolicy) -> anyhow::Result<bool> {
+    let (_, result) = with_retries(retry, || {
+        let mut conn = pool.get_conn();
+        async move { conn.search(dn, Scope::Base, "(objectClass=*)", vec!["1.1"]).await.and_then(|res| res.success()) }
+    })
+    .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(LdapError::LdapResult { result }) if result.rc == NO_SUCH_OBJECT => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("failed to look up {dn}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base_entries() {
+        let entries = base_entries("ou=people,dc=example,dc=org").unwrap();
+        let dns: Vec<&str> = entries.iter().map(|(dn, _)| dn.as_str()).collect();
+        assert_eq!(dns, ["dc=org", "dc=example,dc=org", "ou=people,dc=example,dc=org"]);
+
+        let (_, example) = &entries[1];
+        assert_eq!(example[0].1, HashSet::from(["top", "dcObject", "organization"].map(String::from)));
+        assert_eq!(example[1], ("dc".to_string(), HashSet::from(["example".to_string()])));
+        assert_eq!(example[2], ("o".to_string(), HashSet::from(["example".to_string()])));
+
+        let (_, people) = &entries[2];
+        assert_eq!(people[0].1, HashSet::from(["top", "organizationalUnit"].map(String::from)));
+
+        let entries = base_entries("l=Hamburg,c=DE").unwrap();
+        assert_eq!(entries[0].1[0].1, HashSet::from(["top", "country"].map(String::from)));
+        assert_eq!(entries[1].1[0].1, HashSet::from(["top", "locality"].map(String::from)));
+    }
+
+    #[test]
+    fn test_base_entries_errors() {
+        assert!(base_entries("cn=admin,dc=org").is_err());
+        assert!(base_entries("ou=a+l=b,dc=org").is_err());
+        assert!(base_entries("dc=org,invalid").is_err());
+    }
+
+    #[test]
+    fn test_unescape_value() {
+        assert_eq!(unescape_value("Doe\\, Jane"), "Doe, Jane");
+        assert_eq!(unescape_value("Doe\\2C Jane"), "Doe, Jane");
+        assert_eq!(unescape_value("M\\C3\\BCller"), "Müller");
+        let (dn, attributes) = &base_entries("ou=Sales\\, EMEA,dc=org").unwrap()[1];
+        assert_eq!(dn, "ou=Sales\\, EMEA,dc=org");
+        assert_eq!(attributes[1].1, HashSet::from(["Sales, EMEA".to_string()]));
+    }
+}
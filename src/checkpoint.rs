@@ -0,0 +1,168 @@
+//! Checkpoints of insert runs.
+//!
+//! While inserting, the number of entries processed by the server is saved to a state file along
+//! with the seed and the counters. Since the same seed generates the same entries, an interrupted
+//! run can be resumed by generating the entries again and skipping those that have been processed.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::insert::{InsertCounts, InsertOutcome};
+
+/// How often the state file is written while inserting.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The content of the state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The seed the entries are generated with, `None` if they are read from an LDIF file.
+    pub seed: Option<u64>,
+    /// The base DN of the generated entries or the LDIF file the entries are read from.
+    pub source: String,
+    /// The number of entries that have been processed, successfully or not.
+    pub position: u64,
+    pub counts: InsertCounts,
+}
+
+impl Checkpoint {
+    pub fn new(seed: Option<u64>, source: &str) -> Self {
+        Self { seed, source: source.to_owned(), position: 0, counts: InsertCounts::default() }
+    }
+
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read state file {}", path.display()))?;
+
+        serde_json::from_str(&content).with_context(|| format!("invalid state file {}", path.display()))
+    }
+
+    /// Writes the checkpoint to a temporary file first, so an interruption can't leave a
+    /// truncated state file behind.
+    pub async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let content = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(&tmp, content)
+            .await
+            .with_context(|| format!("failed to write state file {}", path.display()))?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .with_context(|| format!("failed to write state file {}", path.display()))?;
+
+        Ok(())
+    }
+
+    /// Checks that the run to resume inserted the same entries.
+    pub fn check_source(&self, seed: Option<u64>, source: &str) -> anyhow::Result<()> {
+        if self.source != source {
+            bail!("the state file belongs to a run inserting {}, not {source}", self.source);
+        }
+        if seed.is_some() && seed != self.seed {
+            bail!("the seed differs from the seed {:?} of the state file", self.seed);
+        }
+
+        Ok(())
+    }
+}
+
+/// Saves the checkpoint of a running insert to the state file every few seconds.
+///
+/// As the entries are inserted in parallel, they complete out of order. The checkpoint only
+/// advances over entries whose predecessors have all been processed, so resuming it doesn't
+/// skip entries that were still in flight.
+#[derive(Debug)]
+pub struct CheckpointWriter {
+    path: PathBuf,
+    checkpoint: Checkpoint,
+    last_save: Instant,
+    /// The position of the next entry of this run the checkpoint is waiting for.
+    next: u64,
+    /// The results of entries that completed before their predecessors, by position.
+    pending: BTreeMap<u64, (u32, Option<InsertOutcome>)>,
+}
+
+impl CheckpointWriter {
+    pub fn new(path: &Path, checkpoint: Checkpoint) -> Self {
+        Self { path: path.to_owned(), checkpoint, last_save: Instant::now(), next: 0, pending: BTreeMap::new() }
+    }
+
+    /// The counters of the previous runs.
+    pub fn counts(&self) -> InsertCounts {
+        self.checkpoint.counts.clone()
+    }
+
+    /// Records the result of the entry at `position` of this run, see `InsertCounts::record`,
+    /// and saves the checkpoint if it hasn't been saved for a while. Failing to save it is logged
+    /// but doesn't stop the insert.
+    pub async fn update(&mut self, position: u64, retries: u32, outcome: Option<InsertOutcome>) {
+        self.pending.insert(position, (retries, outcome));
+        while let Some((retries, outcome)) = self.pending.remove(&self.next) {
+            self.checkpoint.counts.record(retries, outcome);
+            self.checkpoint.position += 1;
+            self.next += 1;
+        }
+
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.last_save = Instant::now();
+            if let Err(e) = self.checkpoint.save(&self.path).await {
+                warn!("{e:#}");
+            }
+        }
+    }
+
+    /// Saves the final checkpoint.
+    pub async fn finish(self) -> anyhow::Result<()> {
+        self.checkpoint.save(&self.path).await?;
+        info!("saved checkpoint at entry {} to {}", self.checkpoint.position, self.path.display());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let path = TempPath::new("state.json");
+        let mut checkpoint = Checkpoint::new(Some(42), "dc=example,dc=org");
+        checkpoint.position = 3;
+        checkpoint.counts.added = 2;
+        checkpoint.counts.failed = 1;
+
+        checkpoint.save(&path).await.unwrap();
+        let loaded = Checkpoint::load(&path).await.unwrap();
+        assert_eq!(loaded, checkpoint);
+        assert!(loaded.check_source(None, "dc=example,dc=org").is_ok());
+        assert!(loaded.check_source(Some(42), "dc=example,dc=org").is_ok());
+        assert!(loaded.check_source(Some(1), "dc=example,dc=org").is_err());
+        assert!(loaded.check_source(None, "dc=example,dc=com").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_out_of_order() {
+        let mut checkpoint = Checkpoint::new(Some(42), "dc=example,dc=org");
+        checkpoint.position = 10;
+        checkpoint.counts.added = 10;
+        let mut writer = CheckpointWriter::new(Path::new("unused.json"), checkpoint);
+
+        // the first entry of the resumed run is still in flight
+        writer.update(1, 0, Some(InsertOutcome::Added)).await;
+        writer.update(2, 1, None).await;
+        assert_eq!(writer.checkpoint.position, 10);
+        assert_eq!(writer.checkpoint.counts.added, 10);
+
+        writer.update(0, 0, Some(InsertOutcome::Skipped)).await;
+        assert_eq!(writer.checkpoint.position, 13);
+        let counts = &writer.checkpoint.counts;
+        assert_eq!((counts.added, counts.skipped, counts.failed, counts.retried), (11, 1, 1, 1));
+    }
+}
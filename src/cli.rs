@@ -1,6 +1,10 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
-use crate::csv::CsvSender;
+use crate::compression::Compression;
+use crate::config::BindMode;
+use crate::insert::OnExists;
+use crate::ldif::{ByteSize, ChunkLimit};
+use crate::sink::{default_modify_ratio, SinkConfig};
 
 #[derive(Parser)]
 #[clap(version, author, about, long_about = None)]
@@ -8,6 +12,11 @@ pub struct CliArgs {
     #[arg(short, long, default_value_t = String::from("/etc/ldapfill.toml"))]
     /// The config file to use
     pub config_file: String,
+
+    #[arg(short = 'P', long)]
+    /// Use the connection settings, format file and base DN of the profile with this name
+    /// from the configuration file.
+    pub profile: Option<String>,
     
     /// The file specifying the format to use when generating LDAP-Entries. specifying
     /// this will override the file specified in the configuration, if any. Note that this option 
@@ -24,41 +33,296 @@ pub struct CliArgs {
     /// Set the directory to export the csv files to.
     pub csv_directory: String,
 
-    /// The base entry to use when inserting
-    pub base: String,
+    #[arg(short = 'J', long)]
+    /// If set, additionally exports the generated entries into the given file using the
+    /// JSON Lines format, one JSON object per entry.
+    pub jsonl: Option<String>,
+
+    #[arg(short, long = "output", value_name = "TYPE:PATH")]
+    /// Additional outputs to write the generated entries to, e.g. `ldif:out.ldif`,
+    /// `csv:./csv` or `jsonl:out.jsonl`. May be specified multiple times and is combined
+    /// with the sinks configured in the configuration file.
+    pub outputs: Vec<SinkConfig>,
+
+    #[arg(long, value_enum)]
+    /// Compress all output files that neither specify a compression themselves nor end in
+    /// `.gz` or `.zst`. The matching file extension is appended to the file names.
+    pub compress: Option<Compression>,
+
+    /// The base entry to generate the entries below, or to delete. Not required when inserting
+    /// entries from an existing LDIF file or if the base is set in the configuration.
+    pub base: Option<String>,
+
+    #[arg(long)]
+    /// Create the base entry and its missing ancestors, deriving their object classes from
+    /// the RDN, e.g. `dc` -> `dcObject` and `organization`. When exporting, the entries are
+    /// written before the generated ones.
+    pub create_base: bool,
+
+    #[arg(long)]
+    /// Seed the random generator, so the same entries are generated on every run. A random
+    /// seed is used if omitted.
+    pub seed: Option<u64>,
 
     #[command(subcommand)]
     pub cmd: MainCommand
 }
 
 #[derive(Debug, Clone, Subcommand)]
+// parsed once, boxing the connection options wouldn't gain anything
+#[allow(clippy::large_enum_variant)]
 pub enum MainCommand {
     /// Export generated entries into an ldif file.
     Export {
-        /// The file to export to
+        /// The LDIF file to export to. Use `-` to write to stdout. If omitted and no other
+        /// outputs are configured, the LDIF is written to stdout as well.
         #[arg(long)]
-        file: String
+        file: Option<String>,
+
+        /// Split the LDIF file into files containing at most this many entries each.
+        #[arg(long, conflicts_with = "split_size")]
+        split_entries: Option<u64>,
+
+        /// Split the LDIF file into files of at most this size each, e.g. `100M` or `2G`.
+        #[arg(long)]
+        split_size: Option<ByteSize>,
+
+        /// Write `changetype: add` change records instead of content records.
+        #[arg(long)]
+        changes: bool,
+
+        /// Additionally write `changetype: modify` records for a random subset of the entries
+        /// into this file.
+        #[arg(long)]
+        modify_file: Option<String>,
+
+        /// The fraction of entries to write modify records for.
+        #[arg(long, default_value_t = default_modify_ratio(), requires = "modify_file")]
+        modify_ratio: f64,
+
+        /// Additionally write `changetype: delete` records for all entries into this file,
+        /// children first. This allows removing the generated tree again.
+        #[arg(long)]
+        delete_file: Option<String>,
     },
     /// Directly add the generated entries to a running server
     Insert {
+        #[command(flatten)]
+        connection: ConnectionArgs,
+
+        /// What to do with entries that already exist on the server.
+        #[arg(long, value_enum, default_value_t = OnExists::Fail)]
+        on_exists: OnExists,
+
+        /// Insert the entries of this LDIF file instead of generating them. Compressed files
+        /// (`.gz`, `.zst`) are decompressed automatically, `-` reads from stdin.
+        #[arg(long, value_name = "FILE")]
+        from_ldif: Option<String>,
+
+        /// Save the progress to this file every few seconds, so an interrupted run can be
+        /// resumed using `--resume`.
+        #[arg(long, value_name = "FILE")]
+        state_file: Option<String>,
+
+        /// Resume the run saved in the state file, skipping the entries it has processed. Can't be
+        /// combined with other outputs, which would only contain the remaining entries.
+        #[arg(long, requires = "state_file")]
+        resume: bool,
+    },
+    /// Delete the base entry and everything below it from a running server
+    Delete {
+        #[command(flatten)]
+        connection: ConnectionArgs,
+
+        /// Don't ask for confirmation.
         #[arg(short, long)]
-        server: Option<String>,
-        #[arg(short, long)]
-        user: Option<String>,
-        #[arg(short, long)]
-        password: bool,
+        yes: bool,
+
+        /// Delete the entries one by one, even if the server supports the Tree Delete control.
+        #[arg(long)]
+        no_tree_delete: bool,
+    },
+    /// Check that the entries of a previous export exist on a running server with the same values
+    Verify {
+        #[command(flatten)]
+        connection: ConnectionArgs,
+
+        /// The LDIF or JSON Lines (`.jsonl`) export to compare the server with. Compressed files
+        /// (`.gz`, `.zst`) are decompressed automatically, `-` reads LDIF from stdin.
+        #[arg(long = "from", value_name = "FILE")]
+        source: String,
 
-        #[arg(short = 'n', long, default_value_t = 1)]
-        connections: usize
+        /// Don't compare this attribute, e.g. `userPassword` if the server hashes passwords.
+        /// May be specified multiple times.
+        #[arg(long, value_name = "ATTRIBUTE")]
+        ignore: Vec<String>,
+
+        /// Compare the values of this attribute ignoring case and insignificant spaces, e.g.
+        /// `mail` if the server normalizes it. Object classes are always compared this way. May
+        /// be specified multiple times.
+        #[arg(long, value_name = "ATTRIBUTE")]
+        ignore_case: Vec<String>,
     }
 }
 
+/// The options for connecting to a server, shared by the commands doing so. They override the
+/// `[ldap]` section of the configuration.
+#[derive(Debug, Clone, Args)]
+pub struct ConnectionArgs {
+    #[arg(short, long)]
+    pub server: Option<String>,
+    #[arg(short, long)]
+    pub user: Option<String>,
+
+    /// Prompt for the password.
+    #[arg(short, long, group = "password_source")]
+    pub password: bool,
+
+    /// Read the password from the first line of this file. The file must not be accessible
+    /// by other users.
+    #[arg(long, value_name = "FILE", group = "password_source")]
+    pub password_file: Option<String>,
+
+    /// Read the password from this environment variable.
+    #[arg(long, value_name = "VAR", group = "password_source")]
+    pub password_env: Option<String>,
+
+    /// How to authenticate. Defaults to a simple bind if `--user` is set and to an anonymous
+    /// connection otherwise. `sasl-external` uses the identity of an `ldapi://` socket or of
+    /// the TLS client certificate.
+    #[arg(long, value_enum)]
+    pub bind: Option<BindMode>,
+
+    /// The number of connections to open, defaults to 1.
+    #[arg(short = 'n', long)]
+    pub connections: Option<usize>,
+
+    /// How often an operation failing for a transient reason (busy or unavailable server,
+    /// dropped connection) is retried, defaults to 5. Use 0 to disable retries.
+    #[arg(long)]
+    pub retries: Option<u32>,
+
+    /// Send at most this many operations per second, over all connections.
+    #[arg(long, value_name = "OPS")]
+    pub rate: Option<f64>,
+
+    /// Send at most this many operations per second on each connection.
+    #[arg(long, value_name = "OPS")]
+    pub rate_per_connection: Option<f64>,
+
+    /// Raise the rate limits from 1 operation per second to their full value over this many
+    /// seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub ramp_up: Option<u64>,
+
+    /// Abort operations that take longer than this many seconds. Timed out operations are
+    /// retried like other transient errors.
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+
+    /// Upgrade `ldap://` connections using StartTLS. Use an `ldaps://` URL for implicit TLS.
+    #[arg(long, overrides_with = "no_starttls")]
+    pub starttls: bool,
+
+    /// Don't use StartTLS, even if enabled in the configuration file.
+    #[arg(long, overrides_with = "starttls")]
+    pub no_starttls: bool,
+
+    /// PEM file with additional CA certificates to trust.
+    #[arg(long, value_name = "FILE")]
+    pub ca_file: Option<String>,
+
+    /// PEM file with the client certificate to authenticate with.
+    #[arg(long, value_name = "FILE", requires = "client_key")]
+    pub client_cert: Option<String>,
+
+    /// PEM file with the (PKCS #8) key of the client certificate.
+    #[arg(long, value_name = "FILE", requires = "client_cert")]
+    pub client_key: Option<String>,
+
+    /// Don't verify the server certificate. Only use this for test servers.
+    #[arg(long)]
+    pub insecure: bool,
+}
+
 impl CliArgs {
-    pub async fn csv_sender(&self) -> anyhow::Result<Option<CsvSender>> {
+    /// Returns the sinks requested using command line options.
+    pub fn sink_configs(&self) -> Vec<SinkConfig> {
+        let mut sinks = self.outputs.clone();
+
         if self.csv {
-            Ok(Some(crate::csv::start_csv_task(self.csv_directory.as_str()).await?))
-        } else {
-            Ok(None)
+            sinks.push(SinkConfig::Csv { directory: self.csv_directory.clone(), compress: None });
+        }
+
+        if let Some(ref file) = self.jsonl {
+            sinks.push(SinkConfig::Jsonl { file: file.clone(), compress: None });
+        }
+
+        if let MainCommand::Export { ref file, split_entries, split_size, changes, ref modify_file, modify_ratio, ref delete_file } = self.cmd {
+            if let Some(file) = file {
+                let split = split_entries
+                    .map(ChunkLimit::Entries)
+                    .or(split_size.map(ChunkLimit::Size));
+                sinks.push(SinkConfig::Ldif { file: file.clone(), compress: None, split, changes });
+            }
+
+            if let Some(file) = modify_file {
+                sinks.push(SinkConfig::LdifModify { file: file.clone(), compress: None, ratio: modify_ratio });
+            }
+
+            if let Some(file) = delete_file {
+                sinks.push(SinkConfig::LdifDelete { file: file.clone(), compress: None });
+            }
         }
+
+        sinks
+    }
+
+    /// The connection options of the commands talking to a server.
+    pub fn connection_args(&self) -> Option<&ConnectionArgs> {
+        match self.cmd {
+            MainCommand::Insert { ref connection, .. }
+            | MainCommand::Delete { ref connection, .. }
+            | MainCommand::Verify { ref connection, .. } => Some(connection),
+            MainCommand::Export { .. } => None,
+        }
+    }
+
+    /// Whether the command generates entries and therefore needs a format file.
+    pub fn generates_entries(&self) -> bool {
+        match self.cmd {
+            MainCommand::Export { .. } => true,
+            MainCommand::Insert { ref from_ldif, .. } => from_ldif.is_none(),
+            MainCommand::Delete { .. } | MainCommand::Verify { .. } => false,
+        }
+    }
+
+    /// The LDIF file to read the entries from instead of generating them, if any.
+    pub fn ldif_source(&self) -> Option<&str> {
+        match self.cmd {
+            MainCommand::Insert { ref from_ldif, .. } => from_ldif.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The policy for entries that already exist when inserting.
+    pub fn on_exists(&self) -> OnExists {
+        match self.cmd {
+            MainCommand::Insert { on_exists, .. } => on_exists,
+            _ => OnExists::default(),
+        }
+    }
+
+    /// The state file to save the progress of an insert to, and whether to resume from it.
+    pub fn state_file(&self) -> Option<(&str, bool)> {
+        match self.cmd {
+            MainCommand::Insert { ref state_file, resume, .. } => state_file.as_deref().map(|f| (f, resume)),
+            _ => None,
+        }
+    }
+
+    /// Whether the LDIF export should write change records.
+    pub fn change_records(&self) -> bool {
+        matches!(self.cmd, MainCommand::Export { changes: true, .. })
     }
 }
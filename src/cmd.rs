@@ -1,132 +1,395 @@
-use indicatif::{ProgressBar, ProgressStyle};
-use tokio_stream::{wrappers::{ReceiverStream, UnboundedReceiverStream}, StreamExt};
-use tokio::time;
-
-use crate::{cli::{CliArgs, MainCommand}, entries::EntryGenerator, config::LdapConfig, ldap_pool::LdapPool};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::{cli::{CliArgs, MainCommand}, entries::EntryGenerator, config::Config, ldap_pool::LdapPool};
+use crate::base::{base_entries, create_base};
+use crate::checkpoint::{Checkpoint, CheckpointWriter};
+use crate::delete::{collect_subtree, confirm, delete_entries, supports_tree_delete, tree_delete};
+use crate::insert::{InsertOptions, InsertSink};
+use crate::progress::{self, ProgressMessage, ProgressSender};
+use crate::random::random_seed;
+use crate::compression::STDOUT;
+use crate::csv::CsvFormat;
+use crate::jsonl::JsonlParser;
+use crate::ldif::parser::LdifParser;
+use crate::reader::start_reader_task;
+use crate::sink::{SinkConfig, SinkRegistry, SinkSummary};
+use crate::types::EntryReceiver;
+use crate::verify::{verify_entries, CompareOptions};
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::OnceLock;
 
 
-static mut GENERATORS: Option<HashMap<String, EntryGenerator>> = None;
-static mut HIERARCHY: Option<Vec<(String, u64)>> = None;
+static GENERATORS: OnceLock<HashMap<String, EntryGenerator>> = OnceLock::new();
+static HIERARCHY: OnceLock<Vec<(String, u64)>> = OnceLock::new();
+static CSV_FORMAT: OnceLock<CsvFormat> = OnceLock::new();
 
 
 pub fn set_generators(h: HashMap<String,EntryGenerator>) {
-    unsafe {
-        GENERATORS = Some(h);
-    }
+    GENERATORS.set(h).expect("set_generators must only be called once");
 }
 
 pub fn set_hierarchy(h: Vec<(String, u64)>) {
-    unsafe {
-        HIERARCHY = Some(h);
-    }
+    HIERARCHY.set(h).expect("set_hierarchy must only be called once");
+}
+
+pub fn set_csv_format(f: CsvFormat) {
+    CSV_FORMAT.set(f).expect("set_csv_format must only be called once");
 }
 
 pub fn get_generators() -> &'static HashMap<String, EntryGenerator> {
-    unsafe {
-        GENERATORS.as_ref().expect("GENERATORS must be set before calling get_generators")
-    }
+    GENERATORS.get().expect("GENERATORS must be set before calling get_generators")
 }
 
 pub fn get_hierarchy() -> &'static Vec<(String, u64)> {
-    unsafe {
-        HIERARCHY.as_ref().expect("HIERARCHY must be set before calling get_hierarchy")
-    }
+    HIERARCHY.get().expect("HIERARCHY must be set before calling get_hierarchy")
 }
 
+pub fn get_csv_format() -> &'static CsvFormat {
+    CSV_FORMAT.get().expect("CSV_FORMAT must be set before calling get_csv_format")
+}
 
-pub async fn export_cmd(args: &CliArgs) -> anyhow::Result<()> {
-    let ldif_file = match args.cmd {
-        MainCommand::Export { ref file } => file.as_str(),
-        _ => unreachable!()
-    };
+
+pub async fn export_cmd(args: &CliArgs, config: &Config) -> anyhow::Result<()> {
     let count = get_hierarchy().iter().map(|(_, c)| c).product::<u64>();
+    let seed = args.seed.unwrap_or_else(random_seed);
+    let entries = generate_entries(args, config, seed)?;
 
-    let style = ProgressStyle::with_template("{wide_bar} [{pos}/{len}] ({percent}%) {msg} [{elapsed}/{eta}]").expect("valid style");
-    let bar = ProgressBar::new(count);
-    bar.set_style(style);
+    // nothing configured, stream to stdout, e.g. to pipe the output into ldapadd
+    let stdout = SinkConfig::Ldif { file: STDOUT.to_string(), compress: None, split: None, changes: args.change_records() };
+    let mut registry = start_sinks(&sink_configs(args, config, Some(stdout))?, args, seed).await?;
 
-    // Create the export file and generate the entries
-    let csv_sender = args.csv_sender().await?;
-    let ldif_sender = crate::ldif::start_ldif_export_task(ldif_file).await?;
-    let entry_receiver = crate::entries::entry_generator_task(args.base.clone(), get_generators(), get_hierarchy());
+    if args.create_base {
+        for entry in base_entries(require_base(args, config)?)? {
+            registry.send(entry).await?;
+        }
+    }
 
-    let mut entry_stream = ReceiverStream::new(entry_receiver);
-    while let Some(entry) = entry_stream.next().await {
-        if let Some(ref sender) = csv_sender {
-            sender.send(entry.clone()).expect("csv_task to be running");
+    let (progress, progress_task) = progress::start_progress_task(Some(count));
+    let res = fill_sinks(entries, registry, progress, 0).await;
+    progress_task.await?;
+
+    print_summary(&res?);
+
+    Ok(())
+}
+
+pub async fn insert_cmd(args: &CliArgs, config: &Config) -> anyhow::Result<()> {
+    let ldap_config = config.ldap_config(args)?;
+
+    // a resumed run has to generate the same entries again
+    let resumed = match args.state_file() {
+        Some((file, true)) => Some(Checkpoint::load(Path::new(file)).await?),
+        _ => None,
+    };
+    let seed = resumed.as_ref().and_then(|c| c.seed).or(args.seed).unwrap_or_else(random_seed);
+
+    // the number of entries in an LDIF file is unknown until it has been read completely
+    let (entries, reader_task, count, source, seed) = match args.ldif_source() {
+        Some(file) => {
+            let (entries, reader_task) = start_reader_task(file, LdifParser::new()).await?;
+            (entries, Some(reader_task), None, file, None)
+        }
+        None => {
+            let count = get_hierarchy().iter().map(|(_, c)| c).product::<u64>();
+            let base = require_base(args, config)?;
+            (generate_entries(args, config, seed)?, None, Some(count), base, Some(seed))
         }
+    };
 
-        ldif_sender.send(entry).expect("ldif_task to be running");
+    let skip = match resumed {
+        Some(ref checkpoint) => {
+            checkpoint.check_source(args.seed, source)?;
+            info!("resuming after {} processed entries", checkpoint.position);
+            checkpoint.position
+        }
+        None => 0,
+    };
+    let checkpoint = args.state_file().map(|(file, _)| {
+        let checkpoint = resumed.unwrap_or_else(|| Checkpoint::new(seed, source));
+        CheckpointWriter::new(Path::new(file), checkpoint)
+    });
+
+    let pool = LdapPool::new(ldap_config.clone()).await?;
+    let options = InsertOptions { retry: ldap_config.retry(), on_exists: args.on_exists() };
+
+    if args.create_base {
+        let created = create_base(&pool, require_base(args, config)?, &options.retry).await?;
+        info!("created {created} base entries");
+    }
+
+    let (progress, progress_task) = progress::start_progress_task(count);
+    if skip > 0 {
+        drop(progress.send(ProgressMessage::Resume(skip)));
+    }
+    // entries read from an LDIF file have no generator and aren't modified anyway
+    let mut registry = start_sinks(&sink_configs(args, config, None)?, args, seed.unwrap_or_default()).await?;
+    registry.register(Box::new(InsertSink::new(pool, ldap_config.server(), options, checkpoint, progress.clone())));
 
-        bar.inc(1);
+    let res = fill_sinks(entries, registry, progress, skip).await;
+    progress_task.await?;
+
+    let summary = res?;
+    if let Some(reader_task) = reader_task {
+        let read = reader_task.await??;
+        info!("read {read} entries from {}", args.ldif_source().unwrap_or_default());
     }
 
-    bar.finish();
+    print_summary(&summary);
 
     Ok(())
 }
 
-pub async fn insert_cmd(args: &CliArgs) -> anyhow::Result<()> {
-    let count = get_hierarchy().iter().map(|(_, c)| c).product::<u64>();
+pub async fn delete_cmd(args: &CliArgs, config: &Config) -> anyhow::Result<()> {
+    let MainCommand::Delete { yes, no_tree_delete, .. } = args.cmd else { bail!("not a delete command") };
+    let ldap_config = config.ldap_config(args)?;
+    let base = require_base(args, config)?;
+    let server = ldap_config.server();
 
-    let Some(ldap_config) = LdapConfig::from_args(args) else { bail!("user, server, password required") };
-    let pool = LdapPool::new(ldap_config).await?;
+    if !yes && !confirm(&format!("Delete {base} and all entries below it from {server}?"))? {
+        info!("nothing deleted");
+        return Ok(());
+    }
 
-    let style = ProgressStyle::with_template("{wide_bar} [{pos}/{len}] ({percent}%) {msg} [{elapsed}/{eta}]").expect("Valid style");
-    let bar = ProgressBar::new(count);
-    bar.set_style(style);
+    let pool = LdapPool::new(ldap_config.clone()).await?;
+    let retry = ldap_config.retry();
 
+    if !no_tree_delete && supports_tree_delete(&pool, &retry).await? {
+        info!("deleting {base} using the Tree Delete control");
+        tree_delete(&pool, base, &retry).await?;
+        info!("deleted {base} and all entries below it");
+        return Ok(());
+    }
 
-    let csv_sender = args.csv_sender().await?;
-    let entry_receiver = crate::entries::entry_generator_task(args.base.clone(), get_generators(), get_hierarchy());
-    let (entry_sender, result_receiver) = crate::entries::insert_entries_task(pool);
+    let dns = collect_subtree(&pool, base, &retry).await?;
+    if dns.is_empty() {
+        info!("{base} doesn't exist, nothing to delete");
+        return Ok(());
+    }
 
-    // handle the progress bar in its own task 
-    let bar_task = tokio::spawn(async move {
-        // statistics
-        let mut count = 0;
-        let start = time::Instant::now();
-        let mut current_interval = start;
-        let mut current_count = 0;
-        let mut result_stream = UnboundedReceiverStream::new(result_receiver);
+    let (progress, progress_task) = progress::start_progress_task(Some(dns.len() as u64));
+    let counts = delete_entries(Arc::new(pool), dns, retry, progress).await;
+    progress_task.await?;
 
-        while let Some(res) = result_stream.next().await {
-            bar.inc(1);
+    print_summary(&vec![(server.to_owned(), counts.deleted), (format!("{server} (failed)"), counts.failed)]);
 
-            if let Err(e) = res {
-                bar.println(format!("Error: {e}"));
-            }
+    if counts.failed > 0 {
+        bail!("{} entries couldn't be deleted", counts.failed);
+    }
 
+    Ok(())
+}
 
-            count += 1;
-            current_count += 1;
+pub async fn verify_cmd(args: &CliArgs, config: &Config) -> anyhow::Result<()> {
+    let MainCommand::Verify { ref source, ref ignore, ref ignore_case, .. } = args.cmd else { bail!("not a verify command") };
+    let ldap_config = config.ldap_config(args)?;
+    let server = ldap_config.server();
 
-            let now = time::Instant::now();
-            if (now - current_interval).as_secs() >= 1 {
-                let msg = format!("{current_count} entries/second");
-                bar.set_message(msg.clone());
-                current_count = 0;
-                current_interval = now;
-            }
-        }
+    let (entries, reader_task) = if is_jsonl(source) {
+        start_reader_task(source, JsonlParser::default()).await?
+    } else {
+        start_reader_task(source, LdifParser::new()).await?
+    };
 
-        let end = time::Instant::now();
-        let total_duration = end-start;
-        let avg = count/total_duration.as_secs();
-        let msg = format!("Created {avg} entries/second on average");
-        bar.finish_with_message(msg);
-    });
+    let pool = LdapPool::new(ldap_config.clone()).await?;
+    let (progress, progress_task) = progress::start_progress_task(None);
+    let options = CompareOptions { ignored: ignore.clone(), ignore_case: ignore_case.clone() };
+    let counts = verify_entries(Arc::new(pool), entries, ldap_config.retry(), options, progress).await;
+    progress_task.await?;
+
+    let read = reader_task.await??;
+    info!("read {read} entries from {source}");
+
+    print_summary(&vec![
+        (format!("{server} (matching)"), counts.matching),
+        (format!("{server} (missing)"), counts.missing),
+        (format!("{server} (differing)"), counts.differing),
+        (format!("{server} (failed)"), counts.failed),
+    ]);
+
+    let mismatches = counts.missing + counts.differing + counts.failed;
+    if mismatches > 0 {
+        bail!("{mismatches} of {read} entries don't match the server");
+    }
+
+    Ok(())
+}
 
-    let mut entry_stream = ReceiverStream::new(entry_receiver);
+/// Whether `path` is a JSON Lines export, possibly compressed.
+fn is_jsonl(path: &str) -> bool {
+    let path = path.trim_end_matches(".gz").trim_end_matches(".zst");
+    path.ends_with(".jsonl") || path.ends_with(".json")
+}
+
+/// Returns the sinks configured in the configuration file and on the command line, or
+/// `fallback` if there are none.
+fn sink_configs(args: &CliArgs, config: &Config, fallback: Option<SinkConfig>) -> anyhow::Result<Vec<SinkConfig>> {
+    let mut sinks: Vec<SinkConfig> = config.sinks().iter().cloned().chain(args.sink_configs()).collect();
+    if sinks.is_empty() {
+        sinks.extend(fallback);
+    }
+
+    if sinks.iter().filter(|s| s.is_stdout()).count() > 1 {
+        bail!("only one output can be written to stdout");
+    }
+
+    // the outputs would be recreated and only contain the entries after the resumed position
+    if matches!(args.state_file(), Some((_, true))) && !sinks.is_empty() {
+        bail!("--resume can't be combined with outputs, as they would lose the entries written before the interruption");
+    }
+
+    Ok(sinks)
+}
+
+/// Starts `sinks`, compressing their output as requested on the command line.
+async fn start_sinks(sinks: &[SinkConfig], args: &CliArgs, seed: u64) -> anyhow::Result<SinkRegistry> {
+    let mut registry = SinkRegistry::new();
+    for sink in sinks {
+        registry.start(sink, args.compress, seed).await?;
+    }
+
+    Ok(registry)
+}
+
+/// Starts generating the entries below the base DN given on the command line or, if missing,
+/// in the configuration.
+fn generate_entries(args: &CliArgs, config: &Config, seed: u64) -> anyhow::Result<EntryReceiver> {
+    let base = require_base(args, config)?;
+    info!("generating entries using seed {seed}");
+
+    Ok(crate::entries::entry_generator_task(base.to_owned(), get_generators(), get_hierarchy(), seed))
+}
+
+/// The base DN given on the command line or, if missing, in the configuration.
+fn require_base<'a>(args: &'a CliArgs, config: &'a Config) -> anyhow::Result<&'a str> {
+    let base = args.base.as_deref().or(config.defaults().and_then(|d| d.base()));
+    base.ok_or_else(|| anyhow!("no base DN given, pass it on the command line or set `base` in the configuration"))
+}
+
+/// Hands the entries received from `entry_receiver`, except for the first `skip` ones, to all
+/// sinks in `registry`. Closes the sinks afterwards, even if handing an entry to a sink failed.
+async fn fill_sinks(entry_receiver: EntryReceiver, mut registry: SinkRegistry, progress: ProgressSender, skip: u64) -> anyhow::Result<SinkSummary> {
+    let report_progress = !registry.reports_progress();
+
+    let mut result: anyhow::Result<()> = Ok(());
+    let mut entry_stream = ReceiverStream::new(entry_receiver).skip(skip as usize);
     while let Some(entry) = entry_stream.next().await {
-        if let Some(ref csv_sender) = csv_sender {
-            csv_sender.send(entry.clone()).unwrap();
+        if let Err(e) = registry.send(entry).await {
+            result = Err(e);
+            break;
+        }
+
+        if report_progress {
+            drop(progress.send(ProgressMessage::Progress));
+        }
+    }
+    drop(progress);
+
+    let closed = registry.close().await;
+
+    result.and(closed)
+}
+
+fn print_summary(summary: &SinkSummary) {
+    for (destination, count) in summary.iter() {
+        info!("{destination}: {count} entries");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sink::EntrySink;
+    use crate::types::LdapEntry;
+    use async_trait::async_trait;
+    use tokio::sync::mpsc;
+
+    /// A sink failing like a full disk or a closed pipe.
+    struct FailingSink;
+
+    #[async_trait]
+    impl EntrySink for FailingSink {
+        fn name(&self) -> &str {
+            "failing"
         }
 
-        entry_sender.send(entry).await.unwrap();
+        async fn send(&mut self, _: LdapEntry) -> anyhow::Result<()> {
+            Err(anyhow!("broken pipe"))
+        }
+
+        async fn close(self: Box<Self>) -> anyhow::Result<SinkSummary> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failing_sink() {
+        let (tx, rx) = mpsc::channel(1);
+        let sender = tokio::spawn(async move {
+            for i in 0..10 {
+                if tx.send((format!("uid={i},dc=example,dc=org"), vec![])).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut registry = SinkRegistry::new();
+        registry.register(Box::new(FailingSink));
+        let (progress, _) = mpsc::unbounded_channel();
+
+        let err = fill_sinks(rx, registry, progress, 0).await.unwrap_err();
+        assert_eq!(err.to_string(), "broken pipe");
+        // the sender stops instead of waiting for the dropped receiver
+        sender.await.unwrap();
+    }
+
+    fn sinks(cli: &[&str], config: &str) -> anyhow::Result<Vec<SinkConfig>> {
+        use clap::Parser;
+
+        let args = CliArgs::try_parse_from(cli).unwrap();
+        let config: Config = toml::from_str(config).unwrap();
+        let stdout = SinkConfig::Ldif { file: STDOUT.to_string(), compress: None, split: None, changes: false };
+
+        sink_configs(&args, &config, Some(stdout))
     }
-    
 
-    Ok(bar_task.await?)
+    #[test]
+    fn test_stdout_default() {
+        let stdout = SinkConfig::Ldif { file: STDOUT.to_string(), compress: None, split: None, changes: false };
+        assert_eq!(sinks(&["ldapfill", "export"], "").unwrap(), vec![stdout]);
+
+        let file = SinkConfig::Ldif { file: "out.ldif".to_string(), compress: None, split: None, changes: false };
+        assert_eq!(sinks(&["ldapfill", "export", "--file", "out.ldif"], "").unwrap(), vec![file.clone()]);
+        assert_eq!(sinks(&["ldapfill", "-o", "ldif:out.ldif", "export"], "").unwrap(), vec![file.clone()]);
+        assert_eq!(sinks(&["ldapfill", "export"], "[[sinks]]\ntype = \"ldif\"\nfile = \"out.ldif\"\n").unwrap(), vec![file]);
+    }
+
+    #[test]
+    fn test_single_stdout_sink() {
+        assert!(sinks(&["ldapfill", "-o", "jsonl:-", "export"], "").is_ok());
+        assert!(sinks(&["ldapfill", "-o", "jsonl:-", "export", "--file", "-"], "").is_err());
+        assert!(sinks(&["ldapfill", "-J", "-", "export"], "[[sinks]]\ntype = \"ldif-delete\"\nfile = \"-\"\n").is_err());
+        // csv writes a directory of files, `-` is a directory name
+        assert!(sinks(&["ldapfill", "-o", "csv:-", "export", "--file", "-"], "").is_ok());
+    }
+
+    #[test]
+    fn test_resume_without_outputs() {
+        use clap::Parser;
+
+        let resume = |cli: &[&str], config: &str| {
+            let args = CliArgs::try_parse_from(cli).unwrap();
+            sink_configs(&args, &toml::from_str(config).unwrap(), None)
+        };
+
+        assert!(resume(&["ldapfill", "insert", "--state-file", "run.json", "--resume"], "").unwrap().is_empty());
+        assert!(resume(&["ldapfill", "-J", "out.jsonl", "insert", "--state-file", "run.json"], "").is_ok());
+        assert!(resume(&["ldapfill", "-J", "out.jsonl", "insert", "--state-file", "run.json", "--resume"], "").is_err());
+        assert!(resume(
+            &["ldapfill", "insert", "--state-file", "run.json", "--resume"],
+            "[[sinks]]\ntype = \"ldif\"\nfile = \"out.ldif\"\n"
+        )
+        .is_err());
+    }
 }
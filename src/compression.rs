@@ -0,0 +1,163 @@
+//! Transparent compression of output files.
+//!
+//! The compression is either chosen explicitly (using `--compress` or the `compress` key of a
+//! sink in the configuration file) or derived from the file extension: files ending in `.gz`
+//! are compressed using gzip, files ending in `.zst` using zstd.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use clap::ValueEnum;
+use serde::Deserialize;
+use tokio::fs as tfs;
+use tokio::io::{self as tio, AsyncBufRead, AsyncWrite};
+
+/// Writer used by the async exporters. Call `shutdown` when done: it flushes the buffered data
+/// and writes the trailer of compressed files, which are truncated otherwise.
+pub type AsyncOutput = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Reader used to read (possibly compressed) input files.
+pub type AsyncInput = Box<dyn AsyncBufRead + Send + Unpin>;
+
+/// The file name that refers to stdout (or stdin, when reading) instead of a file.
+pub const STDOUT: &str = "-";
+
+/// Returns whether `path` refers to stdout (or stdin).
+pub fn is_stdout(path: &Path) -> bool {
+    path.as_os_str() == STDOUT
+}
+
+/// Returns the name of `path` for log messages.
+pub fn display_name(path: &Path) -> String {
+    if is_stdout(path) {
+        String::from("stdout")
+    } else {
+        path.display().to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Derives the compression from the extension of `path`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// The file extension for this compression, without the leading dot.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+
+    /// Appends the extension for this compression to `path` unless it is already present
+    /// or `path` refers to stdout.
+    pub fn apply_extension(self, path: &Path) -> PathBuf {
+        if is_stdout(path) {
+            return path.to_path_buf();
+        }
+
+        match self.extension() {
+            Some(ext) if path.extension().and_then(|e| e.to_str()) != Some(ext) => {
+                let mut path = path.as_os_str().to_owned();
+                path.push(".");
+                path.push(ext);
+                PathBuf::from(path)
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+}
+
+/// Creates `path` and returns a buffered writer compressing the data as specified by
+/// `compression`. If `path` is `-`, the data is written to stdout instead.
+pub async fn create_async(path: &Path, compression: Compression) -> io::Result<AsyncOutput> {
+    let file: Box<dyn AsyncWrite + Send + Unpin> = if is_stdout(path) {
+        Box::new(tio::BufWriter::new(tio::stdout()))
+    } else {
+        Box::new(tio::BufWriter::new(tfs::File::create(path).await?))
+    };
+
+    let writer: AsyncOutput = match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(GzipEncoder::new(file)),
+        Compression::Zstd => Box::new(ZstdEncoder::new(file)),
+    };
+
+    Ok(writer)
+}
+
+/// Opens `path` for reading, decompressing it according to its extension. If `path` is `-`,
+/// stdin is read instead.
+pub async fn open_async(path: &Path) -> io::Result<AsyncInput> {
+    let file: AsyncInput = if is_stdout(path) {
+        Box::new(tio::BufReader::new(tio::stdin()))
+    } else {
+        Box::new(tio::BufReader::new(tfs::File::open(path).await?))
+    };
+
+    let reader: AsyncInput = match Compression::from_path(path) {
+        Compression::None => file,
+        Compression::Gzip => Box::new(tio::BufReader::new(GzipDecoder::new(file))),
+        Compression::Zstd => Box::new(tio::BufReader::new(ZstdDecoder::new(file))),
+    };
+
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn compression_from_path() {
+        assert_eq!(Compression::from_path(Path::new("out.ldif.gz")), Compression::Gzip);
+        assert_eq!(Compression::from_path(Path::new("out.ldif.zst")), Compression::Zstd);
+        assert_eq!(Compression::from_path(Path::new("out.ldif")), Compression::None);
+    }
+
+    #[test]
+    fn compression_apply_extension() {
+        assert_eq!(Compression::Gzip.apply_extension(Path::new("out.ldif")), PathBuf::from("out.ldif.gz"));
+        assert_eq!(Compression::Gzip.apply_extension(Path::new("out.ldif.gz")), PathBuf::from("out.ldif.gz"));
+        assert_eq!(Compression::None.apply_extension(Path::new("out.ldif")), PathBuf::from("out.ldif"));
+        assert_eq!(Compression::Gzip.apply_extension(Path::new(STDOUT)), PathBuf::from(STDOUT));
+    }
+
+    #[tokio::test]
+    async fn async_output_roundtrip() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let file = TempPath::new(&format!("roundtrip.{}", compression.extension().unwrap()));
+            let mut writer = create_async(&file, compression).await.unwrap();
+            writer.write_all(b"dn: dc=example,dc=org\n\n").await.unwrap();
+            writer.shutdown().await.unwrap();
+
+            // the data is compressed
+            assert_ne!(std::fs::read(&file).unwrap(), b"dn: dc=example,dc=org\n\n");
+
+            // reading decompresses transparently, based on the extension
+            let mut content = String::new();
+            open_async(&file).await.unwrap().read_to_string(&mut content).await.unwrap();
+
+            assert_eq!(content, "dn: dc=example,dc=org\n\n");
+        }
+    }
+}
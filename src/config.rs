@@ -1,10 +1,19 @@
-use crate::cli::MainCommand;
+use crate::cli::ConnectionArgs;
+use crate::password::{PasswordSource, PASSWORD_ENV};
+use crate::controls::parse_control;
+use crate::rate::RateLimit;
+use crate::retry::RetryPolicy;
+use crate::sink::SinkConfig;
 use anyhow::Error;
+use clap::ValueEnum;
+use ldap3::controls::RawControl;
 use log::LevelFilter;
 use serde::Deserialize;
 
 use super::cli::CliArgs;
 
+use std::collections::HashMap;
+use std::time::Duration;
 use std::path::Path;
 
 #[derive(Debug, Deserialize)]
@@ -15,24 +24,127 @@ pub struct Config {
     ldap: Option<LdapConfig>,
 
     defaults: Option<DefaultSettings>,
+
+    #[serde(default)]
+    sinks: Vec<SinkConfig>,
+
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct DefaultSettings {
     #[serde(rename(deserialize = "format-file"))]
     format_file: Option<String>,
+    // The base DN to use if none is given on the command line.
+    base: Option<String>,
+}
+
+/// A named set of settings for one server, selected using `--profile`. The settings of the
+/// profile replace the `[ldap]` section and the defaults of the configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Profile {
+    #[serde(rename(deserialize = "format-file"))]
+    format_file: Option<String>,
+    base: Option<String>,
+    ldap: Option<LdapConfig>,
 }
 
+/// The `[ldap]` section, used by `insert`. All settings can be overridden on the command line.
 #[derive(Debug, Clone, Deserialize)]
 pub struct LdapConfig {
     // The server to connect to
+    #[serde(default)]
     pub server: String,
-    // The user to use for connecting to the server
-    pub user: String,
-    // The password to use when connecting.
-    pub password: String,
+    // The user to use for connecting to the server, only required for simple binds
+    #[serde(default)]
+    pub user: Option<String>,
+    // The password to use when connecting. Only one of `password`, `password-file` and
+    // `password-env` may be set.
+    #[serde(default)]
+    password: Option<String>,
+    // A file containing the password, only readable by the current user.
+    #[serde(default, rename(deserialize = "password-file"))]
+    password_file: Option<String>,
+    // An environment variable containing the password.
+    #[serde(default, rename(deserialize = "password-env"))]
+    password_env: Option<String>,
+    // How to authenticate, defaults to a simple bind if a user is set and anonymous otherwise.
+    #[serde(default)]
+    bind: Option<BindMode>,
 
+    #[serde(default = "default_connections")]
     connections: usize,
+
+    #[serde(default)]
+    tls: TlsConfig,
+
+    #[serde(default)]
+    retry: RetryPolicy,
+
+    #[serde(default, rename(deserialize = "rate-limit"))]
+    rate_limit: RateLimit,
+
+    // The timeout of every operation in seconds.
+    #[serde(default)]
+    timeout: Option<u64>,
+
+    // Controls attached to the operations of the insert, see `crate::controls`.
+    #[serde(default)]
+    controls: Vec<String>,
+}
+
+/// How the connections authenticate after connecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum BindMode {
+    /// Bind using the DN and password of `user`.
+    Simple,
+    /// Don't bind at all.
+    Anonymous,
+    /// SASL EXTERNAL, using the identity of an `ldapi://` socket or the TLS client certificate.
+    SaslExternal,
+}
+
+/// TLS settings of the connections. `ldaps://` URLs always use TLS, `starttls` upgrades
+/// `ldap://` connections using the StartTLS extended operation.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    #[serde(default)]
+    pub starttls: bool,
+    /// PEM file containing additional CA certificates to trust.
+    pub ca_file: Option<String>,
+    /// PEM files containing the client certificate and its (PKCS #8) key.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Skip the verification of the server certificate and hostname, for test servers only.
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+/// The password source given on the command line, if any.
+fn password_source_from_args(args: &CliArgs) -> Option<PasswordSource> {
+    match args.connection_args()? {
+        ConnectionArgs { password: true, .. } => Some(PasswordSource::Prompt),
+        ConnectionArgs { password_file: Some(file), .. } => Some(PasswordSource::File(file.clone())),
+        ConnectionArgs { password_env: Some(var), .. } => Some(PasswordSource::Env(var.clone())),
+        _ => None,
+    }
+}
+
+impl TlsConfig {
+    /// Overwrites the settings with the options given on the command line. `--starttls` and
+    /// `--no-starttls` override the configuration, the last one given wins.
+    fn merge(&mut self, args: &ConnectionArgs) {
+        if args.starttls || args.no_starttls {
+            self.starttls = args.starttls;
+        }
+        self.insecure |= args.insecure;
+        self.ca_file = args.ca_file.clone().or(self.ca_file.take());
+        self.client_cert = args.client_cert.clone().or(self.client_cert.take());
+        self.client_key = args.client_key.clone().or(self.client_key.take());
+    }
 }
 
 impl Config {
@@ -46,93 +158,407 @@ impl Config {
         self.log
     }
 
+    /// Applies the settings of the profile `name`. Fails if there is no such profile.
+    pub fn apply_profile(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            bail!("unknown profile {name}, available profiles: {}", names.join(", "));
+        };
+
+        let defaults = self.defaults.get_or_insert_with(DefaultSettings::default);
+        if profile.format_file.is_some() {
+            defaults.format_file = profile.format_file;
+        }
+
+        if profile.base.is_some() {
+            defaults.base = profile.base;
+        }
+
+        if profile.ldap.is_some() {
+            self.ldap = profile.ldap;
+        }
+
+        Ok(())
+    }
+
     pub fn ldap(&self) -> Option<&LdapConfig> {
         self.ldap.as_ref()
     }
 
+    /// Returns the connection settings for `insert`: the `[ldap]` section, overridden by the
+    /// options given on the command line, with the password read from its source.
+    pub fn ldap_config(&self, args: &CliArgs) -> anyhow::Result<LdapConfig> {
+        let mut ldap_config = self.ldap_settings(args)?;
+        let env = std::env::var_os(PASSWORD_ENV).map(|_| PasswordSource::Env(PASSWORD_ENV.to_string()));
+        ldap_config.resolve_password(password_source_from_args(args), env)?;
+
+        Ok(ldap_config)
+    }
+
+    /// Returns the validated connection settings like `ldap_config`, without reading the password.
+    fn ldap_settings(&self, args: &CliArgs) -> anyhow::Result<LdapConfig> {
+        let mut ldap_config = self.ldap().cloned().unwrap_or_default();
+        ldap_config.merge_args(args);
+
+        if ldap_config.server.is_empty() {
+            bail!("no server given, use --server or set `server` in the [ldap] section of the configuration");
+        }
+
+        if ldap_config.connections == 0 {
+            bail!("at least one connection is required");
+        }
+
+        ldap_config.rate_limit.validate()?;
+        ldap_config.controls()?;
+        if ldap_config.timeout == Some(0) {
+            bail!("the timeout must be at least one second");
+        }
+
+        Ok(ldap_config)
+    }
+
     pub fn defaults(&self) -> Option<&DefaultSettings> {
         self.defaults.as_ref()
     }
+
+    /// The sinks that should be used in addition to the ones specified on the command line.
+    pub fn sinks(&self) -> &[SinkConfig] {
+        &self.sinks
+    }
 }
 
-impl LdapConfig {
-    /// Tries to create an `LdapConfig` using the provided `CliArgs`.
-    /// Returns `None` if the cli args are missing one or more parameters.
-    pub fn from_args(args: &CliArgs) -> Option<Self> {
-        if let MainCommand::Insert {
-            server,
-            user,
-            password,
-            connections,
-        } = &args.cmd
-        {
-            let Some(user) = user.clone() else { return None; };
-            let Some(server) = server.clone() else { return None; };
-            let connections = *connections;
-            let password = match password {
-                true => rpassword::prompt_password(format!("Password for {server}: ")).unwrap(),
-                false => "".to_string(),
-            };
-
-            Some(Self {
-                user,
-                server,
-                password,
-                connections,
-            })
-        } else {
-            None
+impl Default for LdapConfig {
+    fn default() -> Self {
+        Self {
+            server: String::new(),
+            user: None,
+            password: None,
+            password_file: None,
+            password_env: None,
+            bind: None,
+            connections: default_connections(),
+            tls: TlsConfig::default(),
+            retry: RetryPolicy::default(),
+            rate_limit: RateLimit::default(),
+            timeout: None,
+            controls: vec![],
         }
     }
+}
 
+impl LdapConfig {
     /// Merges the present values of `args` with `self`, effectively overwriting
     /// values.
     pub fn merge_args(&mut self, args: &CliArgs) {
-        if let MainCommand::Insert {
-            server,
-            user,
-            password,
-            ..
-        } = &args.cmd
+        if let Some(
+            connection @ ConnectionArgs {
+                server,
+                user,
+                bind,
+                connections,
+                retries,
+                rate,
+                rate_per_connection,
+                ramp_up,
+                timeout,
+                ..
+            },
+        ) = args.connection_args()
         {
             if let Some(ref user) = user {
-                self.user = user.to_owned();
+                self.user = Some(user.to_owned());
+            }
+
+            if let Some(bind) = bind {
+                self.bind = Some(*bind);
+            }
+
+            if let Some(connections) = connections {
+                self.connections = *connections;
+            }
+
+            if let Some(retries) = retries {
+                self.retry.max_retries = *retries;
+            }
+
+            if let Some(rate) = rate {
+                self.rate_limit.ops_per_second = Some(*rate);
+            }
+
+            if let Some(rate) = rate_per_connection {
+                self.rate_limit.per_connection = Some(*rate);
+            }
+
+            if let Some(ramp_up) = ramp_up {
+                self.rate_limit.ramp_up = *ramp_up;
+            }
+
+            if let Some(timeout) = timeout {
+                self.timeout = Some(*timeout);
             }
 
             if let Some(ref server) = server {
                 self.server = server.to_owned();
             }
 
-            if *password {
-                self.password =
-                    rpassword::prompt_password(format!("Password for {}: ", self.server)).unwrap();
-            }
+            self.tls.merge(connection);
         }
     }
 
+    /// Reads the password from the source with the highest precedence, see `crate::password`,
+    /// and stores it in `password`. `cli` is the source given on the command line and `env` the
+    /// `LDAPFILL_PASSWORD` variable, if set. Anonymous and SASL EXTERNAL binds don't need a
+    /// password.
+    fn resolve_password(&mut self, cli: Option<PasswordSource>, env: Option<PasswordSource>) -> anyhow::Result<()> {
+        let source = match cli.or(env) {
+            Some(source) => Some(source),
+            None => self.password_source()?,
+        };
+
+        let source = match source {
+            Some(source) => source,
+            None if self.bind() != BindMode::Simple => return Ok(()),
+            None => match PasswordSource::interactive_prompt() {
+                Some(prompt) => prompt,
+                None => bail!("no password given for {}, use --password-file, --password-env or {PASSWORD_ENV}", self.server),
+            },
+        };
+
+        self.password = Some(source.read(&self.server)?);
+        self.password_file = None;
+        self.password_env = None;
+
+        Ok(())
+    }
+
+    /// The password source of the configuration file, if any.
+    fn password_source(&self) -> anyhow::Result<Option<PasswordSource>> {
+        let sources: Vec<PasswordSource> = [
+            self.password.clone().map(PasswordSource::Value),
+            self.password_file.clone().map(PasswordSource::File),
+            self.password_env.clone().map(PasswordSource::Env),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if sources.len() > 1 {
+            bail!("only one of password, password-file and password-env may be set");
+        }
+
+        Ok(sources.into_iter().next())
+    }
+
     pub fn server(&self) -> &str {
         self.server.as_str()
     }
 
-    pub fn user(&self) -> &str {
-        self.user.as_str()
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// The configured bind mode, or a simple bind if a user is set and anonymous otherwise.
+    pub fn bind(&self) -> BindMode {
+        match (self.bind, &self.user) {
+            (Some(bind), _) => bind,
+            (None, Some(_)) => BindMode::Simple,
+            (None, None) => BindMode::Anonymous,
+        }
     }
 
     pub fn password(&self) -> &str {
-        self.password.as_str()
+        self.password.as_deref().unwrap_or_default()
     }
 
     pub fn connections(&self) -> usize {
         self.connections
     }
+
+    pub fn tls(&self) -> &TlsConfig {
+        &self.tls
+    }
+
+    pub fn retry(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate_limit
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    pub fn controls(&self) -> anyhow::Result<Vec<RawControl>> {
+        self.controls.iter().map(|c| parse_control(c)).collect()
+    }
 }
 
 impl DefaultSettings {
     pub fn format_file(&self) -> Option<&str> {
         self.format_file.as_deref()
     }
+
+    pub fn base(&self) -> Option<&str> {
+        self.base.as_deref()
+    }
 }
 
 fn default_log() -> LevelFilter {
     LevelFilter::Info
 }
+
+fn default_connections() -> usize {
+    1
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+[ldap]
+server = "ldap://ldap.example.org"
+user = "cn=admin,dc=example,dc=org"
+password = "secret"
+connections = 4
+controls = ["relax-rules", "!manage-dsa-it"]
+timeout = 30
+
+[ldap.tls]
+starttls = true
+
+[ldap.rate-limit]
+ops-per-second = 500
+ramp-up = 60
+"#;
+
+    #[test]
+    fn test_ldap_config_from_file() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let args = CliArgs::try_parse_from(["ldapfill", "dc=example,dc=org", "insert"]).unwrap();
+        let ldap_config = config.ldap_settings(&args).unwrap();
+
+        assert_eq!(ldap_config.server(), "ldap://ldap.example.org");
+        assert_eq!(ldap_config.user(), Some("cn=admin,dc=example,dc=org"));
+        assert_eq!(ldap_config.connections(), 4);
+        assert!(ldap_config.tls().starttls);
+        assert_eq!(ldap_config.rate_limit(), RateLimit { ops_per_second: Some(500.0), per_connection: None, ramp_up: 60 });
+        assert_eq!(ldap_config.timeout(), Some(Duration::from_secs(30)));
+        let controls = ldap_config.controls().unwrap();
+        assert_eq!(controls.iter().map(|c| c.crit).collect::<Vec<_>>(), [false, true]);
+    }
+
+    #[test]
+    fn test_ldap_config_overridden_by_args() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let args = CliArgs::try_parse_from([
+            "ldapfill", "dc=example,dc=org", "insert", "-s", "ldaps://other.example.org", "-n", "2", "--insecure",
+            "--rate-per-connection", "100", "--timeout", "5",
+        ])
+        .unwrap();
+        let ldap_config = config.ldap_settings(&args).unwrap();
+
+        assert_eq!(ldap_config.server(), "ldaps://other.example.org");
+        assert_eq!(ldap_config.user(), Some("cn=admin,dc=example,dc=org"));
+        assert_eq!(ldap_config.connections(), 2);
+        assert!(ldap_config.tls().starttls && ldap_config.tls().insecure);
+        assert_eq!(ldap_config.rate_limit().ops_per_second, Some(500.0));
+        assert_eq!(ldap_config.rate_limit().per_connection, Some(100.0));
+        assert_eq!(ldap_config.timeout(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_starttls_overridden_by_args() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let starttls = |flags: &[&str]| {
+            let args = CliArgs::try_parse_from(["ldapfill", "dc=example,dc=org", "insert"].iter().chain(flags)).unwrap();
+            config.ldap_settings(&args).unwrap().tls().starttls
+        };
+
+        assert!(starttls(&[]));
+        assert!(!starttls(&["--no-starttls"]));
+        assert!(starttls(&["--no-starttls", "--starttls"]));
+        assert!(!starttls(&["--starttls", "--no-starttls"]));
+
+        let plain: Config = toml::from_str("[ldap]\nserver = \"ldap://localhost\"").unwrap();
+        let args = CliArgs::try_parse_from(["ldapfill", "dc=example,dc=org", "insert", "--starttls"]).unwrap();
+        assert!(plain.ldap_settings(&args).unwrap().tls().starttls);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut config: Config = toml::from_str(&format!(
+            r#"{CONFIG}
+[defaults]
+format-file = "format.toml"
+
+[profiles.win22]
+base = "dc=win22,dc=example,dc=org"
+format-file = "format-ad.toml"
+
+[profiles.win22.ldap]
+server = "ldaps://win22.example.org"
+user = "cn=Administrator,cn=Users,dc=win22,dc=example,dc=org"
+password-env = "LDAPFILL_TEST_WIN22_PASSWORD"
+"#
+        ))
+        .unwrap();
+
+        assert!(config.apply_profile("openldap").is_err());
+        config.apply_profile("win22").unwrap();
+
+        let defaults = config.defaults().unwrap();
+        assert_eq!(defaults.format_file(), Some("format-ad.toml"));
+        assert_eq!(defaults.base(), Some("dc=win22,dc=example,dc=org"));
+
+        let args = CliArgs::try_parse_from(["ldapfill", "insert"]).unwrap();
+        let ldap_config = config.ldap_settings(&args).unwrap();
+        // the password of the profile is read from the environment
+        assert_eq!(
+            ldap_config.password_source().unwrap(),
+            Some(PasswordSource::Env("LDAPFILL_TEST_WIN22_PASSWORD".to_string()))
+        );
+        assert_eq!(ldap_config.server(), "ldaps://win22.example.org");
+        assert_eq!(ldap_config.connections(), 1);
+        assert!(!ldap_config.tls().starttls);
+    }
+
+    #[test]
+    fn test_password_precedence() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let args = CliArgs::try_parse_from(["ldapfill", "insert"]).unwrap();
+        let resolve = |cli: Option<&str>, env: Option<&str>| {
+            let mut ldap_config = config.ldap_settings(&args).unwrap();
+            let source = |password: &str| PasswordSource::Value(password.to_string());
+            ldap_config.resolve_password(cli.map(source), env.map(source)).map(|_| ldap_config)
+        };
+
+        assert_eq!(resolve(Some("from-cli"), Some("from-env")).unwrap().password(), "from-cli");
+        assert_eq!(resolve(None, Some("from-env")).unwrap().password(), "from-env");
+        assert_eq!(resolve(None, None).unwrap().password(), "secret");
+
+        let args = CliArgs::try_parse_from(["ldapfill", "insert", "--password-env", "LDAPFILL_TEST_CLI_PASSWORD"]).unwrap();
+        assert_eq!(password_source_from_args(&args), Some(PasswordSource::Env("LDAPFILL_TEST_CLI_PASSWORD".to_string())));
+
+        let ambiguous: Config =
+            toml::from_str("[ldap]\nserver = \"ldap://localhost\"\npassword = \"a\"\npassword-env = \"B\"").unwrap();
+        assert!(ambiguous.ldap_settings(&args).unwrap().resolve_password(None, None).is_err());
+
+        // anonymous binds don't need a password
+        let anonymous: Config = toml::from_str("[ldap]\nserver = \"ldap://localhost\"").unwrap();
+        assert!(anonymous.ldap_settings(&args).unwrap().resolve_password(None, None).is_ok());
+    }
+
+    #[test]
+    fn test_ldap_config_requires_server() {
+        let config: Config = toml::from_str("log = \"info\"").unwrap();
+        let args = CliArgs::try_parse_from(["ldapfill", "dc=example,dc=org", "insert"]).unwrap();
+
+        assert!(config.ldap_settings(&args).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! Request controls attached to the operations of `insert`.
+//!
+//! Controls are configured like the `-e` option of the OpenLDAP tools: either one of the names
+//! below or the OID of the control, prefixed with `!` to mark it as critical. A critical control
+//! that isn't supported by the server makes the operation fail instead of being ignored.
+
+use ldap3::controls::{ManageDsaIt, RawControl, RelaxRules};
+
+/// Active Directory's `LDAP_SERVER_PERMISSIVE_MODIFY_OID`, which ignores adding existing and
+/// deleting missing values.
+const PERMISSIVE_MODIFY_OID: &str = "1.2.840.113556.1.4.1413";
+
+/// Parses a control like `relax-rules`, `!manage-dsa-it` or `1.2.840.113556.1.4.1413`.
+pub fn parse_control(control: &str) -> anyhow::Result<RawControl> {
+    let (critical, name) = match control.trim().strip_prefix('!') {
+        Some(name) => (true, name),
+        None => (false, control.trim()),
+    };
+
+    let mut control = match name.to_ascii_lowercase().as_str() {
+        "relax-rules" | "relax" => RawControl::from(RelaxRules),
+        "manage-dsa-it" | "managedsait" => RawControl::from(ManageDsaIt),
+        "permissive-modify" => RawControl { ctype: PERMISSIVE_MODIFY_OID.to_string(), crit: false, val: None },
+        oid if is_oid(oid) => RawControl { ctype: oid.to_string(), crit: false, val: None },
+        _ => bail!("unknown control {name:?}, use relax-rules, manage-dsa-it, permissive-modify or an OID"),
+    };
+    control.crit = critical;
+
+    Ok(control)
+}
+
+/// Whether `s` is a numeric OID like `1.2.840.113556.1.4.1413`.
+fn is_oid(s: &str) -> bool {
+    s.contains('.') && s.split('.').all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_control() {
+        let relax = parse_control("relax-rules").unwrap();
+        assert_eq!((relax.ctype.as_str(), relax.crit), ("1.3.6.1.4.1.4203.666.5.12", false));
+
+        let manage = parse_control("!manage-dsa-it").unwrap();
+        assert_eq!((manage.ctype.as_str(), manage.crit), ("2.16.840.1.113730.3.4.2", true));
+
+        let permissive = parse_control("permissive-modify").unwrap();
+        assert_eq!(permissive.ctype, PERMISSIVE_MODIFY_OID);
+
+        let custom = parse_control("!1.2.840.113556.1.4.529").unwrap();
+        assert_eq!((custom.ctype.as_str(), custom.crit), ("1.2.840.113556.1.4.529", true));
+
+        assert!(parse_control("tree-delete").is_err());
+        assert!(parse_control("1..2").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+//! Exports the credentials of generated users into a small csv file, e.g. to drive bind tests
+//! with JMeter or Gatling.
+//!
+//! Every line contains the DN followed by the selected attributes, by default `uid` and
+//! `userPassword`. Values are written as generated, i.e. passwords are exported in plaintext
+//! before the server had a chance to hash them. Only entries of the selected object classes
+//! are written, optionally only every n-th of them.
+
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::io as tio;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::compression::{self, Compression};
+use crate::sink::{ChannelSink, SinkSummary};
+use crate::types::LdapEntry;
+
+pub type CredentialsReceiver = UnboundedReceiver<LdapEntry>;
+
+pub fn default_credential_classes() -> Vec<String> {
+    vec![String::from("inetOrgPerson")]
+}
+
+pub fn default_credential_attributes() -> Vec<String> {
+    vec![String::from("uid"), String::from("userPassword")]
+}
+
+pub fn default_credential_sampling() -> u64 {
+    1
+}
+
+/// Selects which entries and attributes are written.
+#[derive(Debug, Clone)]
+pub struct CredentialFilter {
+    /// Object classes of the exported entries, compared case-insensitively.
+    pub classes: Vec<String>,
+    /// The attributes written after the DN.
+    pub attributes: Vec<String>,
+    /// Only every n-th matching entry is written.
+    pub every: u64,
+}
+
+impl CredentialFilter {
+    fn matches(&self, entry: &LdapEntry) -> bool {
+        entry
+            .1
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("objectclass"))
+            .flat_map(|(_, classes)| classes.iter())
+            .any(|class| self.classes.iter().any(|c| c.eq_ignore_ascii_case(class)))
+    }
+}
+
+pub async fn start_credentials_export_task<P: AsRef<Path>>(
+    export_file: P,
+    compression: Compression,
+    filter: CredentialFilter,
+) -> anyhow::Result<ChannelSink> {
+    if filter.every == 0 {
+        bail!("credential sampling must be at least 1");
+    }
+
+    let (tx, rx) = unbounded_channel();
+    let destination = compression::display_name(export_file.as_ref());
+    let name = format!("credentials export to {destination}");
+
+    let writer = compression::create_async(export_file.as_ref(), compression).await?;
+
+    let task = tokio::spawn(async move { credentials_exporter(rx, writer, destination, filter).await });
+
+    Ok(ChannelSink::new(name, tx, task))
+}
+
+async fn credentials_exporter<O: tio::AsyncWriteExt + Unpin>(
+    rx: CredentialsReceiver,
+    mut writer: O,
+    destination: String,
+    filter: CredentialFilter,
+) -> anyhow::Result<SinkSummary> {
+    let header = std::iter::once("dn").chain(filter.attributes.iter().map(String::as_str));
+    writer
+        .write_all(&build_record(header)?)
+        .await
+        .with_context(|| format!("failed to write header to {destination}"))?;
+
+    let mut stream = UnboundedReceiverStream::new(rx);
+    let (mut matched, mut count) = (0, 0);
+    while let Some(entry) = stream.next().await {
+        if !filter.matches(&entry) {
+            continue;
+        }
+
+        matched += 1;
+        if (matched - 1) % filter.every != 0 {
+            continue;
+        }
+
+        let record = build_record(credential_values(&entry, &filter.attributes))
+            .with_context(|| format!("failed to serialize credentials of {}", entry.0))?;
+        writer
+            .write_all(&record)
+            .await
+            .with_context(|| format!("failed to write credentials to {destination}"))?;
+        count += 1;
+    }
+
+    writer.shutdown().await.with_context(|| format!("failed to flush {destination}"))?;
+
+    Ok(vec![(destination, count)])
+}
+
+/// Returns the DN and the values of `attributes`. Missing attributes are empty, of multiple
+/// values the first in alphabetical order is used.
+fn credential_values<'e>(entry: &'e LdapEntry, attributes: &'e [String]) -> impl Iterator<Item = &'e str> {
+    let (dn, entry_attributes) = entry;
+    let values = attributes.iter().map(|attribute| {
+        entry_attributes
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(attribute))
+            .flat_map(|(_, values)| values.iter())
+            .min()
+            .map(String::as_str)
+            .unwrap_or_default()
+    });
+
+    std::iter::once(dn.as_str()).chain(values)
+}
+
+/// Serializes a single csv record, including the line terminator.
+fn build_record<'v>(values: impl Iterator<Item = &'v str>) -> anyhow::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(values)?;
+
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::sink::EntrySink;
+    use crate::testing::TempPath;
+
+    fn user(i: u64) -> LdapEntry {
+        (
+            format!("uid=user{i},dc=example,dc=org"),
+            vec![
+                ("objectClass".to_string(), HashSet::from(["inetOrgPerson".to_string()])),
+                ("uid".to_string(), HashSet::from([format!("user{i}")])),
+                ("userpassword".to_string(), HashSet::from([format!("secret,{i}")])),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_credentials_export_sampling() {
+        let file = TempPath::new("credentials.csv");
+        let filter = CredentialFilter {
+            classes: default_credential_classes(),
+            attributes: default_credential_attributes(),
+            every: 2,
+        };
+        let mut sink: Box<dyn EntrySink> =
+            Box::new(start_credentials_export_task(&file, Compression::None, filter).await.unwrap());
+
+        sink.send(("ou=users,dc=example,dc=org".to_string(), vec![])).await.unwrap();
+        for i in 0..5 {
+            sink.send(user(i)).await.unwrap();
+        }
+
+        let summary = sink.close().await.unwrap();
+        let content = std::fs::read_to_string(&file).unwrap();
+
+        assert_eq!(summary, vec![(file.display().to_string(), 3)]);
+        assert_eq!(
+            content,
+            "dn,uid,userPassword\n\
+             \"uid=user0,dc=example,dc=org\",user0,\"secret,0\"\n\
+             \"uid=user2,dc=example,dc=org\",user2,\"secret,2\"\n\
+             \"uid=user4,dc=example,dc=org\",user4,\"secret,4\"\n"
+        );
+    }
+}
@@ -3,166 +3,697 @@
 //! Entries written to a csv file whose name is equivalent to the object class.
 //! Users can specify to which directory csv files will be written.
 //!
-//! When an object class is first written, the csv writer will iterate through all fields,
-//! write a header and retain the order of the fields for subsequent writes.
+//! When an object class is first written, the csv writer determines the columns, writes a header
+//! and retains the order of the columns for subsequent writes. The columns, their headers and the
+//! layout of the files can be configured in the `[csv]` section of the format file, see
+//! `CsvFormat`. Classes whose columns aren't known in advance are spooled, see `Spool`.
 
 use std::collections::{HashMap, HashSet};
-use std::fs;
 use std::path::{Path, PathBuf};
 
+use anyhow::Context;
+use serde::Deserialize;
 use tokio::fs as tfs;
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use tokio::task;
+use tokio::io::{self as tio, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
 use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
 
+use crate::compression::{self, AsyncOutput, Compression};
+use crate::dn::split_dn;
+use crate::entries::EntryGenerator;
+use crate::sink::{ChannelSink, SinkSummary};
 use crate::types::LdapEntry;
 
-pub type CsvSender = UnboundedSender<LdapEntry>;
 pub type CsvReceiver = UnboundedReceiver<LdapEntry>;
-pub type Writer = csv::Writer<std::fs::File>;
+
+/// The csv file of a single object class.
+struct Writer {
+    object_class: String,
+    output: AsyncOutput,
+    count: u64,
+    /// Holds the records until all columns are known, if the class has no fixed columns.
+    spool: Option<Spool>,
+}
+
+/// The records of an object class whose columns are extended by every new attribute, i.e.
+/// classes without a generator or configured columns. The records are written to a temporary
+/// file, and only the length and the number of columns of every record are kept in memory.
+/// Once all entries have been received, the header and the records, padded to the final number
+/// of columns, are copied into the csv file.
+struct Spool {
+    path: PathBuf,
+    file: tio::BufWriter<tfs::File>,
+    records: Vec<(usize, usize)>,
+}
+
+impl Writer {
+    /// Serializes `record` using `builder` and writes it to the file or the spool.
+    async fn write_record(&mut self, builder: &csv::WriterBuilder, record: &[String]) -> anyhow::Result<()> {
+        let bytes = serialize(builder, record)?;
+
+        match self.spool {
+            Some(ref mut spool) => {
+                spool.file.write_all(&bytes).await?;
+                spool.records.push((bytes.len(), record.len()));
+            }
+            None => self.output.write_all(&bytes).await?,
+        }
+
+        Ok(())
+    }
+
+    /// Writes the spooled records, if any, and flushes the file. `headers` is the final header
+    /// row of the class.
+    async fn finish(mut self, builder: &csv::WriterBuilder, delimiter: u8, headers: &[String]) -> anyhow::Result<()> {
+        if let Some(mut spool) = self.spool.take() {
+            spool.file.flush().await?;
+            self.output.write_all(&serialize(builder, headers)?).await?;
+
+            let mut reader = tio::BufReader::new(tfs::File::open(&spool.path).await?);
+            let mut record = vec![];
+            for (len, columns) in spool.records {
+                record.resize(len, 0);
+                reader.read_exact(&mut record).await?;
+
+                if columns < headers.len() {
+                    // replace the line terminator with the delimiters and cells of the missing
+                    // columns, serialized like any other empty cells
+                    let padding = serialize(builder, &vec![String::new(); headers.len() - columns + 1])?;
+                    let start = padding.iter().position(|b| *b == delimiter).unwrap_or_default();
+                    record.pop();
+                    record.extend_from_slice(&padding[start..]);
+                }
+
+                self.output.write_all(&record).await?;
+            }
+
+            tfs::remove_file(&spool.path).await?;
+        }
+
+        self.output.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+/// The `[csv]` section of the format file.
+///
+/// ```toml
+/// [csv]
+/// delimiter = ";"
+/// quote = "always"
+/// separator = "|"
+/// rdn = true
+///
+/// [csv.inetOrgPerson]
+/// columns = ["dn", "uid", "cn", "mail"]
+/// headers = { dn = "DN", uid = "Login" }
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CsvFormat {
+    /// The field delimiter, has to be an ASCII character.
+    delimiter: char,
+    quote: QuoteStyle,
+    /// Joins the values of multi-valued attributes.
+    separator: String,
+    /// Whether to add a `dn` column containing the DN of the entry.
+    dn: bool,
+    /// Whether to add a `rdn` column containing the value of the RDN of the entry.
+    rdn: bool,
+    /// Whether to add a `parent` column containing the DN of the parent entry.
+    parent: bool,
+    /// Settings for individual object classes.
+    #[serde(flatten)]
+    classes: HashMap<String, CsvClassFormat>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CsvClassFormat {
+    /// The columns in the order they are written. Besides attributes, `dn`, `rdn` and `parent`
+    /// may be used. If set, the `dn`, `rdn` and `parent` flags are ignored.
+    columns: Option<Vec<String>>,
+    /// Header names for columns, defaults to the column name.
+    headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuoteStyle {
+    Always,
+    Necessary,
+    NonNumeric,
+    Never,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            quote: QuoteStyle::Necessary,
+            separator: String::from("|"),
+            dn: true,
+            rdn: false,
+            parent: true,
+            classes: HashMap::new(),
+        }
+    }
+}
+
+impl CsvFormat {
+    /// Checks the settings that can't be validated while deserializing.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.delimiter.is_ascii() {
+            bail!("csv delimiter must be an ASCII character, got `{}`", self.delimiter);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configuration of `object_class`, comparing the names case-insensitively.
+    fn class(&self, object_class: &str) -> Option<&CsvClassFormat> {
+        self.classes
+            .iter()
+            .find(|(class, _)| class.eq_ignore_ascii_case(object_class))
+            .map(|(_, format)| format)
+    }
+
+    /// Returns the columns for `object_class`. Unless configured otherwise, the columns are
+    /// derived from the generator of the class, so they contain every attribute the generator
+    /// may produce. Without a generator (e.g. when inserting from an LDIF file), the columns
+    /// start with the attributes of the first entry and grow with every new attribute.
+    fn layout(
+        &self,
+        object_class: &str,
+        generator: Option<&EntryGenerator>,
+        attributes: &[(String, HashSet<String>)],
+    ) -> CsvLayout {
+        let class = self.class(object_class);
+        let configured = class.and_then(|c| c.columns.clone());
+        let explicit = configured.is_some();
+
+        let names = match configured {
+            Some(columns) => columns,
+            None => {
+                let attributes: Vec<&str> = match generator {
+                    Some(generator) => std::iter::once("objectclass").chain(generator.attribute_names()).collect(),
+                    None => attributes.iter().map(|(k, _)| k.as_str()).collect(),
+                };
+
+                let mut names: Vec<String> = [(self.dn, "dn"), (self.rdn, "rdn"), (self.parent, "parent")]
+                    .into_iter()
+                    .filter(|(enabled, _)| *enabled)
+                    .map(|(_, name)| name.to_string())
+                    .collect();
+
+                // attribute names are case-insensitive, only keep the first spelling
+                for attribute in attributes {
+                    if !names.iter().any(|n| n.eq_ignore_ascii_case(attribute)) {
+                        names.push(attribute.to_owned());
+                    }
+                }
+
+                names
+            }
+        };
+
+        let headers = names.iter().map(|name| self.header(object_class, name)).collect();
+        let columns = names.into_iter().map(Column::from).collect();
+
+        let growing = !explicit && generator.is_none();
+
+        CsvLayout { columns, headers, explicit, growing, ignored: HashSet::new() }
+    }
+
+    /// Returns the header of the column `name` of `object_class`.
+    fn header(&self, object_class: &str, name: &str) -> String {
+        self.class(object_class)
+            .and_then(|c| c.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)))
+            .map(|(_, header)| header.as_str())
+            .unwrap_or(name)
+            .to_owned()
+    }
+}
+
+/// Determines the object class whose csv file `attributes` is written to. Entries usually have
+/// several object classes (e.g. `top` and `inetOrgPerson`), so classes with a csv configuration
+/// or a generator are preferred. Otherwise, the first class in alphabetical order except for
+/// `top` is used.
+fn entry_object_class<'e>(
+    attributes: &'e [(String, HashSet<String>)],
+    format: &CsvFormat,
+    generators: &HashMap<String, EntryGenerator>,
+) -> Option<&'e str> {
+    let mut classes: Vec<&str> = attributes
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("objectclass"))
+        .flat_map(|(_, v)| v.iter().map(String::as_str))
+        .collect();
+    classes.sort_unstable();
+
+    let known = classes.iter().find(|class| {
+        format.class(class).is_some() || generators.keys().any(|g| g.eq_ignore_ascii_case(class))
+    });
+
+    known
+        .or_else(|| classes.iter().find(|class| !class.eq_ignore_ascii_case("top")))
+        .or(classes.first())
+        .copied()
+}
+
+/// A column of a csv file.
+#[derive(Debug, Clone, PartialEq)]
+enum Column {
+    Dn,
+    Rdn,
+    Parent,
+    Attribute(String),
+}
+
+impl From<String> for Column {
+    fn from(name: String) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "dn" => Column::Dn,
+            "rdn" => Column::Rdn,
+            "parent" => Column::Parent,
+            _ => Column::Attribute(name),
+        }
+    }
+}
+
+/// The columns of the csv file of one object class.
+#[derive(Debug)]
+struct CsvLayout {
+    columns: Vec<Column>,
+    headers: Vec<String>,
+    /// Whether the columns have been configured in the format file.
+    explicit: bool,
+    /// Whether new attributes get a column, as neither a generator nor the configuration
+    /// determine the columns.
+    growing: bool,
+    /// Attributes without a column that have already been reported.
+    ignored: HashSet<String>,
+}
+
+impl CsvLayout {
+    fn has_column(&self, name: &str) -> bool {
+        self.columns.iter().any(|c| matches!(c, Column::Attribute(a) if a.eq_ignore_ascii_case(name)))
+    }
+
+    /// Adds a column for every attribute of `attributes` that doesn't have one yet, if the
+    /// layout is growing. `header` returns the header of a new column.
+    fn add_columns(&mut self, attributes: &[(String, HashSet<String>)], header: impl Fn(&str) -> String) {
+        if !self.growing {
+            return;
+        }
+
+        for (name, _) in attributes {
+            if !self.has_column(name) {
+                self.columns.push(Column::Attribute(name.to_owned()));
+                self.headers.push(header(name));
+            }
+        }
+    }
+
+    /// Returns the attributes of `attributes` without a column that haven't been returned
+    /// before. Only layouts derived from a generator report them, configured columns are a
+    /// deliberate choice and growing layouts have a column for every attribute.
+    fn new_ignored_attributes(&mut self, attributes: &[(String, HashSet<String>)]) -> Vec<String> {
+        if self.explicit || self.growing {
+            return vec![];
+        }
+
+        let mut new = vec![];
+        for (name, _) in attributes {
+            if !self.has_column(name) && self.ignored.insert(name.to_ascii_lowercase()) {
+                new.push(name.to_owned());
+            }
+        }
+
+        new
+    }
+
+    /// Builds the record for `entry` in the order of the columns. Missing attributes are
+    /// written as empty cells, multiple values are joined using `separator`.
+    fn record(&self, entry: &LdapEntry, separator: &str) -> Vec<String> {
+        let (dn, attributes) = entry;
+        let (rdn, parent) = split_dn(dn);
+
+        self.columns
+            .iter()
+            .map(|column| match column {
+                Column::Dn => dn.to_owned(),
+                Column::Rdn => rdn.split_once('=').map(|(_, v)| v).unwrap_or(rdn).to_owned(),
+                Column::Parent => parent.to_owned(),
+                Column::Attribute(name) => {
+                    // missing attributes result in an empty cell
+                    let mut values: Vec<&str> = attributes
+                        .iter()
+                        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+                        .flat_map(|(_, values)| values.iter().map(String::as_str))
+                        .collect();
+                    values.sort_unstable();
+                    values.join(separator)
+                }
+            })
+            .collect()
+    }
+}
 
 /// Starts the csv export task. This function checks if the `target_dir` exists and tries to
-/// create it if it doesen't. It starts the export task on a background task and returns the sender
-/// handle that allows sending ldap entries to serialize to the task. When the last sender has been
-/// dropped, the task will stop.
-pub async fn start_csv_task<P: AsRef<Path>>(target_dir: P) -> anyhow::Result<CsvSender> {
+/// create it if it doesen't. It starts the export task on a background task and returns a sink
+/// that allows sending ldap entries to serialize to the task. When the sink is closed, the task
+/// will stop.
+pub async fn start_csv_task<P: AsRef<Path>>(
+    target_dir: P,
+    compression: Compression,
+    format: &'static CsvFormat,
+    generators: &'static HashMap<String, EntryGenerator>,
+) -> anyhow::Result<ChannelSink> {
     let (sender, receiver) = mpsc::unbounded_channel();
     let path = target_dir.as_ref().to_path_buf();
+    let name = format!("CSV export to {}", path.display());
 
     // create directory if it does not exist
     if !path.exists() {
         tfs::create_dir_all(path.as_path()).await?;
     }
 
-    tokio::spawn(async move { csv_exporter(path, receiver).await });
+    let task = tokio::spawn(async move { csv_exporter(path, compression, format, generators, receiver).await });
 
-    Ok(sender)
+    Ok(ChannelSink::new(name, sender, task))
 }
 
-async fn csv_exporter(export_path: PathBuf, receiver: CsvReceiver) {
-    match csv_exporter_inner(export_path, receiver).await {
-        Ok(_) => (),
-        Err(e) => error!("Failed to export csv: {e}"),
-    }
-}
-
-async fn csv_exporter_inner(export_path: PathBuf, receiver: CsvReceiver) -> anyhow::Result<()> {
+async fn csv_exporter(
+    export_path: PathBuf,
+    compression: Compression,
+    format: &CsvFormat,
+    generators: &HashMap<String, EntryGenerator>,
+    receiver: CsvReceiver,
+) -> anyhow::Result<SinkSummary> {
     let mut stream = UnboundedReceiverStream::new(receiver);
-    // the list of writers, with the associated object class
-    let mut writers: Vec<(String, Writer)> = Vec::new();
+    let mut writers: Vec<Writer> = Vec::new();
     // keep track of classes and in which order to serialize them
-    let mut object_classes: HashMap<String, Vec<String>> = HashMap::new();
+    let mut layouts: HashMap<String, CsvLayout> = HashMap::new();
     let mut last_flush = Instant::now();
+    let builder = record_builder(format);
+
+    while let Some(entry) = stream.next().await {
+        let (dn, attributes) = &entry;
+        let Some(object_class) = entry_object_class(attributes, format, generators) else {
+            bail!("{dn} has no object class");
+        };
 
-    while let Some((dn, mut attributes)) = stream.next().await {
-        let object_class = attributes.iter().find(|(k, _)| k.to_lowercase() == "objectclass").map(|(_, v)| v.iter().next().unwrap().as_str()).unwrap();
+        // object class names are case-insensitive as well
+        let layout = layouts.entry(object_class.to_ascii_lowercase()).or_insert_with(|| {
+            let generator = generators.iter().find(|(c, _)| c.eq_ignore_ascii_case(object_class)).map(|(_, g)| g);
+            format.layout(object_class, generator, attributes)
+        });
 
-        if !object_classes.contains_key(object_class) {
-            handle_new_object_class(&mut object_classes, object_class, &attributes);
+        layout.add_columns(attributes, |name| format.header(object_class, name));
+        for attribute in layout.new_ignored_attributes(attributes) {
+            warn!("{object_class}.csv has no column for {attribute}, values are not exported");
         }
 
         // get the writer
-        let writer = if let Some(w) = writers
-            .iter_mut()
-            .find(|(c, _)| *c == object_class)
-            .map(|(_, w)| w)
-        {
-            w
-        } else {
-            let file = format!("{object_class}.csv");
-            match task::block_in_place(|| open_new_writer(export_path.join(file))) {
-                Ok(mut w) => {
-                    let order = &object_classes[object_class];
-
-                    if let Err(e) = w.write_record(order) {
-                        warn!("Failed to write header row for {object_class}: {e}");
-                    }
+        let index = writers.iter().position(|w| w.object_class.eq_ignore_ascii_case(object_class));
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let file = csv_file(&export_path, object_class, compression);
+                let spool = layout.growing.then(|| spool_file(&export_path, object_class));
+                let mut w = open_new_writer(&file, object_class, compression, spool.as_deref())
+                    .await
+                    .with_context(|| format!("failed to create {}", file.display()))?;
 
-                    writers.push((object_class.to_owned(), w));
-                    writers
-                        .iter_mut()
-                        .find(|(c, _)| *c == object_class)
-                        .map(|(_, w)| w)
-                        .unwrap()
-                }
-                Err(_) => {
-                    // ignore the error and try again next time we encounter this class
-                    continue;
+                // the header of a spooled class is written once all of its columns are known
+                if !layout.growing {
+                    w.write_record(&builder, &layout.headers)
+                        .await
+                        .with_context(|| format!("failed to write header row for {object_class}"))?;
                 }
+
+                writers.push(w);
+                writers.len() - 1
             }
         };
+        let writer = &mut writers[index];
 
-        let order = &object_classes[object_class];
-        let mut record: Vec<&str> = Vec::with_capacity(order.len());
-
-        attributes.push((String::from("dn"), HashSet::from([dn.clone()])));
-        attributes.push((String::from("rdn"), HashSet::from([dn.split_once(",").unwrap().1.to_owned()])));
-        // put reference in the correct `order` for the csv file
-        // we do this by iterating through the attributes of the determined order,
-        // look for the current attribute in the list of attributes of the received entry
-        // and pushing the value into the record Vec.
-        for attribute in order.iter() {
-            // O(n), I guess
-            // There must always be at least one entry
-            let value = attributes
-                .iter()
-                .find(|(k, _)| *k == *attribute)
-                .map(|(_, v)| v.iter().next().unwrap())
-                .unwrap();
-            record.push(value);
-        }
-
-        if let Err(e) = task::block_in_place(|| writer.write_record(record)) {
-            warn!("Failed to write csv record: {e}");
-        }
+        let record = layout.record(&entry, &format.separator);
+        writer
+            .write_record(&builder, &record)
+            .await
+            .with_context(|| format!("failed to write csv record for {dn}"))?;
+        writer.count += 1;
 
         // flush every 5 seconds to minimize data loss if we only write few objects during a long
         // operation (e.g. generating 200k entries, only 20 of which are at the top level and
-        // don't trigger a flush by the Writer on their own.)
+        // don't trigger a flush of the buffered output on their own.)
         let now = Instant::now();
         if (now - last_flush).as_secs() >= 5 {
-            task::block_in_place(|| {
-                writers.iter_mut().for_each(|(s, w)| {
-                    if let Err(e) = w.flush() {
-                        warn!("Failed to flush {s} writer: {e}");
-                    }
-                })
-            });
-
+            flush_writers(&mut writers).await?;
             last_flush = now;
         }
     }
 
+    // the channel has been closed and no more records will be written
+    let mut summary = Vec::with_capacity(writers.len());
+    for writer in writers {
+        let file = csv_file(&export_path, &writer.object_class, compression);
+        let count = writer.count;
+        let headers = &layouts[&writer.object_class.to_ascii_lowercase()].headers;
+        writer
+            .finish(&builder, format.delimiter as u8, headers)
+            .await
+            .with_context(|| format!("failed to finish {}", file.display()))?;
+
+        summary.push((file.display().to_string(), count));
+    }
+
+    Ok(summary)
+}
+
+/// Returns the path of the csv file for `object_class`.
+fn csv_file(export_path: &Path, object_class: &str, compression: Compression) -> PathBuf {
+    compression.apply_extension(&export_path.join(format!("{object_class}.csv")))
+}
+
+/// Returns the path of the temporary file spooling the records of `object_class`.
+fn spool_file(export_path: &Path, object_class: &str) -> PathBuf {
+    export_path.join(format!(".{object_class}.csv.spool"))
+}
+
+async fn flush_writers(writers: &mut [Writer]) -> anyhow::Result<()> {
+    for writer in writers.iter_mut() {
+        let flushed = match writer.spool {
+            Some(ref mut spool) => spool.file.flush().await,
+            None => writer.output.flush().await,
+        };
+        flushed.with_context(|| format!("failed to flush {} writer", writer.object_class))?;
+    }
+
     Ok(())
 }
 
-fn handle_new_object_class(
-    class_map: &mut HashMap<String, Vec<String>>,
-    new_class: &str,
-    new_attributes: &[(String, HashSet<String>)],
-) {
-    let mut order: Vec<String> = new_attributes
-        .iter()
-        .map(|(s, _)| s)
-        .map(String::to_owned)
-        .collect();
-    
-    order.insert(0, String::from("rdn"));
-    order.insert(0, String::from("dn"));
+async fn open_new_writer(file: &Path, object_class: &str, compression: Compression, spool: Option<&Path>) -> anyhow::Result<Writer> {
+    let output = compression::create_async(file, compression).await?;
+    let spool = match spool {
+        Some(path) => Some(Spool {
+            path: path.to_path_buf(),
+            file: tio::BufWriter::new(tfs::File::create(path).await?),
+            records: vec![],
+        }),
+        None => None,
+    };
+
+    Ok(Writer { object_class: object_class.to_owned(), output, count: 0, spool })
+}
+
+/// Serializes a single csv record, including the line terminator.
+fn serialize(builder: &csv::WriterBuilder, record: &[String]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = builder.from_writer(vec![]);
+    writer.write_record(record)?;
+
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
 
-    class_map.insert(new_class.to_owned(), order);
+/// Returns the builder of the csv writers serializing the records, as configured by `format`.
+fn record_builder(format: &CsvFormat) -> csv::WriterBuilder {
+    let quote_style = match format.quote {
+        QuoteStyle::Always => csv::QuoteStyle::Always,
+        QuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+        QuoteStyle::NonNumeric => csv::QuoteStyle::NonNumeric,
+        QuoteStyle::Never => csv::QuoteStyle::Never,
+    };
+
+    let mut builder = csv::WriterBuilder::new();
+    builder.delimiter(format.delimiter as u8).quote_style(quote_style);
+
+    builder
 }
 
-fn open_new_writer(file: PathBuf) -> anyhow::Result<Writer> {
-    let file = fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(file)?;
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempPath;
+
+    fn entry() -> LdapEntry {
+        (
+            "uid=test,ou=users,dc=example,dc=org".to_string(),
+            vec![
+                ("objectclass".to_string(), HashSet::from(["inetOrgPerson".to_string()])),
+                ("uid".to_string(), HashSet::from(["test".to_string()])),
+                ("mail".to_string(), HashSet::from(["b@example.org".to_string(), "a@example.org".to_string()])),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_default_layout() {
+        let format = CsvFormat::default();
+        let entry = entry();
+        let layout = format.layout("inetOrgPerson", None, &entry.1);
+
+        assert_eq!(layout.headers, vec!["dn", "parent", "objectclass", "uid", "mail"]);
+        assert_eq!(
+            layout.record(&entry, &format.separator),
+            vec![
+                "uid=test,ou=users,dc=example,dc=org",
+                "ou=users,dc=example,dc=org",
+                "inetOrgPerson",
+                "test",
+                "a@example.org|b@example.org"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_configured_layout() {
+        let format: CsvFormat = toml::from_str(
+            r#"
+            delimiter = ";"
+            quote = "non-numeric"
+            separator = " "
+
+            [inetOrgPerson]
+            columns = ["rdn", "mail", "cn", "dn"]
+            headers = { rdn = "Login", mail = "E-Mail" }
+            "#,
+        )
+        .unwrap();
+        let entry = entry();
+        let layout = format.layout("inetOrgPerson", None, &entry.1);
 
-    let writer = csv::WriterBuilder::new().from_writer(file);
+        assert_eq!(format.delimiter, ';');
+        assert_eq!(format.quote, QuoteStyle::NonNumeric);
+        assert_eq!(layout.headers, vec!["Login", "E-Mail", "cn", "dn"]);
+        assert_eq!(
+            layout.record(&entry, &format.separator),
+            vec!["test", "a@example.org b@example.org", "", "uid=test,ou=users,dc=example,dc=org"]
+        );
+    }
+
+    #[test]
+    fn test_attribute_variation() {
+        use crate::modifiers::parser::Token;
+
+        let format = CsvFormat::default();
+        let generators = HashMap::from([(
+            "inetOrgPerson".to_string(),
+            EntryGenerator::new(
+                "inetOrgPerson".to_string(),
+                "uid".to_string(),
+                HashMap::from([
+                    ("uid".to_string(), Token::String("test".to_string())),
+                    ("mail".to_string(), Token::String("test@example.org".to_string())),
+                    ("description".to_string(), Token::String("optional".to_string())),
+                ]),
+            ),
+        )]);
+        let (dn, mut attributes) = entry();
+        attributes.push(("objectClass".to_string(), HashSet::from(["top".to_string()])));
+        // differently spelled attribute names refer to the same attribute
+        attributes.push(("MAIL".to_string(), HashSet::from(["c@example.org".to_string()])));
+
+        let object_class = entry_object_class(&attributes, &format, &generators).unwrap();
+        assert_eq!(object_class, "inetOrgPerson");
+
+        let mut layout = format.layout(object_class, generators.get(object_class), &attributes);
+        assert_eq!(layout.headers, vec!["dn", "parent", "objectclass", "uid", "description", "mail"]);
+        assert_eq!(
+            layout.record(&(dn, attributes.clone()), &format.separator),
+            vec![
+                "uid=test,ou=users,dc=example,dc=org",
+                "ou=users,dc=example,dc=org",
+                "inetOrgPerson|top",
+                "test",
+                "",
+                "a@example.org|b@example.org|c@example.org"
+            ]
+        );
+
+        attributes.push(("telephoneNumber".to_string(), HashSet::from(["123".to_string()])));
+        assert_eq!(layout.new_ignored_attributes(&attributes), vec!["telephoneNumber"]);
+        assert!(layout.new_ignored_attributes(&attributes).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_export() {
+        use crate::sink::EntrySink;
+        use tokio::io::AsyncReadExt;
+
+        let directory = TempPath::new("csv");
+        let format: &'static CsvFormat = Box::leak(Box::default());
+        let generators: &'static HashMap<String, EntryGenerator> = Box::leak(Box::default());
+        let mut sink: Box<dyn EntrySink> =
+            Box::new(start_csv_task(&directory, Compression::Gzip, format, generators).await.unwrap());
 
-    Ok(writer)
+        sink.send(entry()).await.unwrap();
+        let summary = sink.close().await.unwrap();
+
+        let file = directory.join("inetOrgPerson.csv.gz");
+        let mut content = String::new();
+        compression::open_async(&file).await.unwrap().read_to_string(&mut content).await.unwrap();
+
+        assert_eq!(summary, vec![(file.display().to_string(), 1)]);
+        assert_eq!(
+            content,
+            "dn,parent,objectclass,uid,mail\n\
+             \"uid=test,ou=users,dc=example,dc=org\",\"ou=users,dc=example,dc=org\",inetOrgPerson,test,a@example.org|b@example.org\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_growing_export() {
+        use crate::sink::EntrySink;
+
+        let directory = TempPath::new("csv-growing");
+        let format: &'static CsvFormat = Box::leak(Box::default());
+        let generators: &'static HashMap<String, EntryGenerator> = Box::leak(Box::default());
+        let mut sink: Box<dyn EntrySink> =
+            Box::new(start_csv_task(&directory, Compression::None, format, generators).await.unwrap());
+
+        let (dn, mut attributes) = entry();
+        sink.send((dn.clone(), attributes.clone())).await.unwrap();
+        // entries without a generator may have attributes the first entry of the class lacks
+        attributes.push(("telephoneNumber".to_string(), HashSet::from(["123".to_string()])));
+        sink.send((dn, attributes)).await.unwrap();
+        let summary = sink.close().await.unwrap();
+
+        let file = directory.join("inetOrgPerson.csv");
+        let content = std::fs::read_to_string(&file).unwrap();
+        let spooled = spool_file(&directory, "inetOrgPerson").exists();
+
+        assert_eq!(summary, vec![(file.display().to_string(), 2)]);
+        assert!(!spooled);
+        assert_eq!(
+            content,
+            "dn,parent,objectclass,uid,mail,telephoneNumber\n\
+             \"uid=test,ou=users,dc=example,dc=org\",\"ou=users,dc=example,dc=org\",inetOrgPerson,test,a@example.org|b@example.org,\n\
+             \"uid=test,ou=users,dc=example,dc=org\",\"ou=users,dc=example,dc=org\",inetOrgPerson,test,a@example.org|b@example.org,123\n"
+        );
+    }
 }
@@ -0,0 +1,240 @@
+//! Deletes a subtree from the server, e.g. to remove the generated entries after a test.
+//!
+//! Servers supporting the Tree Delete control delete the whole subtree in a single operation.
+//! Otherwise the DNs of the subtree are collected using a paged search and deleted leaves first:
+//! the entries of the deepest level are deleted in parallel, then those of the level above and
+//! so on, until the base entry is deleted last.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context;
+use ldap3::adapters::{Adapter, EntriesOnly, PagedResults};
+use ldap3::controls::RawControl;
+use ldap3::{LdapError, Scope, SearchEntry};
+use tokio::task::JoinSet;
+
+use crate::dn::split_dn;
+use crate::ldap_pool::LdapPool;
+use crate::progress::{ProgressMessage, ProgressSender};
+use crate::result_codes::NO_SUCH_OBJECT;
+use crate::retry::{with_retries, RetryPolicy};
+
+/// The OID of the Tree Delete control, supported by Active Directory among others.
+const TREE_DELETE_OID: &str = "1.2.840.113556.1.4.805";
+
+/// The number of DNs requested per page when collecting the subtree.
+const PAGE_SIZE: i32 = 500;
+
+/// Asks whether to continue. Without a terminal, `--yes` is required.
+pub fn confirm(question: &str) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("not asking for confirmation without a terminal, use --yes to delete anyway");
+    }
+
+    eprint!("{question} [y/N] ");
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Whether the root DSE lists the Tree Delete control as supported.
+pub async fn supports_tree_delete(pool: &LdapPool, retry: &RetryPolicy) -> anyhow::Result<bool> {
+    let (_, result) = with_retries(retry, || {
+        let mut conn = pool.get_conn();
+        async move { conn.search("", Scope::Base, "(objectClass=*)", vec!["supportedControl"]).await?.success() }
+    })
+    .await;
+    let (entries, _) = result.context("failed to read the root DSE")?;
+
+    Ok(entries
+        .into_iter()
+        .map(SearchEntry::construct)
+        .any(|entry| has_control(&entry.attrs, TREE_DELETE_OID)))
+}
+
+fn has_control(attrs: &HashMap<String, Vec<String>>, oid: &str) -> bool {
+    attrs
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("supportedControl"))
+        .any(|(_, values)| values.iter().any(|v| v == oid))
+}
+
+/// Deletes `base` along with all entries below it using the Tree Delete control.
+pub async fn tree_delete(pool: &LdapPool, base: &str, retry: &RetryPolicy) -> anyhow::Result<()> {
+    let (_, result) = with_retries(retry, || async move {
+        let mut conn = pool.get_write_conn().await;
+        conn.with_controls(tree_delete_controls(pool.controls()))
+            .delete(base)
+            .await
+            .and_then(|res| res.success())
+    })
+    .await;
+    result.with_context(|| format!("failed to delete {base}"))?;
+
+    Ok(())
+}
+
+/// Returns the configured `controls` along with the Tree Delete control, as setting the controls
+/// of a connection replaces the ones attached by the pool.
+fn tree_delete_controls(controls: &[RawControl]) -> Vec<RawControl> {
+    let mut controls = controls.to_vec();
+    controls.push(RawControl { ctype: TREE_DELETE_OID.to_string(), crit: true, val: None });
+
+    controls
+}
+
+/// Collects the DNs of `base` and all entries below it using a paged search. Returns an empty
+/// list if `base` doesn't exist.
+pub async fn collect_subtree(pool: &LdapPool, base: &str, retry: &RetryPolicy) -> anyhow::Result<Vec<String>> {
+    // an interrupted search starts from the beginning
+    let (_, result) = with_retries(retry, || async move {
+        let mut conn = pool.get_conn();
+        let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![Box::new(EntriesOnly::new()), Box::new(PagedResults::new(PAGE_SIZE))];
+        let mut stream = conn.streaming_search_with(adapters, base, Scope::Subtree, "(objectClass=*)", vec!["1.1"]).await?;
+
+        let mut dns = vec![];
+        while let Some(entry) = stream.next().await? {
+            dns.push(SearchEntry::construct(entry).dn);
+        }
+        stream.finish().await.success()?;
+
+        Ok(dns)
+    })
+    .await;
+
+    match result {
+        Ok(dns) => Ok(dns),
+        Err(LdapError::LdapResult { result }) if result.rc == NO_SUCH_OBJECT => Ok(vec![]),
+        Err(e) => Err(e).with_context(|| format!("failed to search below {base}")),
+    }
+}
+
+/// Groups `dns` by their number of RDNs, the deepest level first.
+fn levels(dns: Vec<String>) -> Vec<Vec<String>> {
+    let mut levels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for dn in dns {
+        levels.entry(depth(&dn)).or_default().push(dn);
+    }
+
+    levels.into_values().rev().collect()
+}
+
+fn depth(dn: &str) -> usize {
+    let mut depth = 0;
+    let mut rest = dn;
+    while !rest.is_empty() {
+        rest = split_dn(rest).1;
+        depth += 1;
+    }
+
+    depth
+}
+
+/// The number of deleted entries and of entries that couldn't be deleted.
+#[derive(Debug, Default)]
+pub struct DeleteCounts {
+    pub deleted: u64,
+    pub failed: u64,
+}
+
+/// Deletes `dns` leaves first, using one task per connection of `pool`. Failed deletes are
+/// printed above the progress bar. Entries below an entry that couldn't be deleted can't be
+/// deleted either, so they fail as well.
+pub async fn delete_entries(pool: Arc<LdapPool>, dns: Vec<String>, retry: RetryPolicy, progress: ProgressSender) -> DeleteCounts {
+    let mut counts = DeleteCounts::default();
+
+    for level in levels(dns) {
+        let level = Arc::new(level);
+        let next = Arc::new(AtomicUsize::new(0));
+
+        let mut workers = JoinSet::new();
+        for _ in 0..pool.connections() {
+            let (pool, level, next, progress) = (pool.clone(), level.clone(), next.clone(), progress.clone());
+            workers.spawn(async move {
+                let mut counts = DeleteCounts::default();
+
+                while let Some(dn) = level.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let (_, result) = with_retries(&retry, || async {
+                        pool.get_write_conn().await.delete(dn).await.and_then(|res| res.success())
+                    })
+                    .await;
+
+                    let message = match result {
+                        // deleted in the meantime
+                        Ok(_) | Err(LdapError::LdapResult { result: ldap3::LdapResult { rc: NO_SUCH_OBJECT, .. } }) => {
+                            counts.deleted += 1;
+                            ProgressMessage::Progress
+                        }
+                        Err(e) => {
+                            counts.failed += 1;
+                            ProgressMessage::ProgressWithMessage(format!("Error deleting {dn}: {e}"))
+                        }
+                    };
+                    drop(progress.send(message));
+                }
+
+                counts
+            });
+        }
+
+        while let Some(worker) = workers.join_next().await {
+            match worker {
+                Ok(worker_counts) => {
+                    counts.deleted += worker_counts.deleted;
+                    counts.failed += worker_counts.failed;
+                }
+                Err(e) => error!("delete task failed: {e}"),
+            }
+        }
+    }
+
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let dns = ["dc=example,dc=org", "uid=a,ou=people,dc=example,dc=org", "ou=people,dc=example,dc=org", "cn=Doe\\, Jane,dc=example,dc=org", "uid=b,ou=people,dc=example,dc=org"];
+        let levels = levels(dns.iter().map(|dn| dn.to_string()).collect());
+
+        assert_eq!(
+            levels,
+            vec![
+                vec!["uid=a,ou=people,dc=example,dc=org".to_string(), "uid=b,ou=people,dc=example,dc=org".to_string()],
+                vec!["ou=people,dc=example,dc=org".to_string(), "cn=Doe\\, Jane,dc=example,dc=org".to_string()],
+                vec!["dc=example,dc=org".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_has_control() {
+        let attrs = HashMap::from([(
+            "supportedcontrol".to_string(),
+            vec!["1.2.840.113556.1.4.319".to_string(), TREE_DELETE_OID.to_string()],
+        )]);
+
+        assert!(has_control(&attrs, TREE_DELETE_OID));
+        assert!(!has_control(&attrs, "1.3.6.1.4.1.4203.666.5.12"));
+        assert!(!has_control(&HashMap::new(), TREE_DELETE_OID));
+    }
+
+    #[test]
+    fn test_tree_delete_controls() {
+        let relax = RawControl { ctype: "1.3.6.1.4.1.4203.666.5.12".to_string(), crit: false, val: None };
+        let controls = tree_delete_controls(&[relax]);
+
+        let oids: Vec<&str> = controls.iter().map(|c| c.ctype.as_str()).collect();
+        assert_eq!(oids, ["1.3.6.1.4.1.4203.666.5.12", TREE_DELETE_OID]);
+        assert!(controls[1].crit);
+    }
+}
@@ -0,0 +1,27 @@
+//! Helpers for distinguished names.
+
+/// Splits `dn` into its RDN and the DN of the parent, respecting escaped commas.
+pub fn split_dn(dn: &str) -> (&str, &str) {
+    let mut escaped = false;
+    for (i, c) in dn.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            ',' if !escaped => return (&dn[..i], dn[i + 1..].trim_start()),
+            _ => escaped = false,
+        }
+    }
+
+    (dn, "")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_dn() {
+        assert_eq!(split_dn("uid=test,dc=example,dc=org"), ("uid=test", "dc=example,dc=org"));
+        assert_eq!(split_dn("cn=Doe\\, Jane, dc=org"), ("cn=Doe\\, Jane", "dc=org"));
+        assert_eq!(split_dn("dc=org"), ("dc=org", ""));
+    }
+}
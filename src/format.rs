@@ -2,6 +2,7 @@ use std::{collections::HashMap, path::Path};
 use serde::Deserialize;
 use toml::Deserializer;
 
+use crate::csv::CsvFormat;
 use crate::entries::EntryGenerator;
 use crate::modifiers::parser;
 
@@ -14,10 +15,14 @@ pub type Fields = HashMap<String, HashMap<String, String>>;
 ///
 /// The `weight` describes how many entries are to be generated for 
 /// each level in the hierarchy.
+///
+/// The optional `csv` section configures the layout of the csv export.
 #[derive(Debug, Deserialize)]
 pub struct Format {
     hierarchy: Vec<String>,
     count: Vec<u64>,
+    #[serde(default)]
+    csv: CsvFormat,
     #[serde(flatten)]
     fields: Fields
 }
@@ -34,6 +39,8 @@ impl Format {
             bail!("count and hierarchy must have the same number of elements");
         }
 
+        format.csv.validate()?;
+
         for class in &format.hierarchy[..] {
             if !format.fields.contains_key(class) {
                 bail!("All values of hierarchy must correspond to an object class. Could not find: {class}");
@@ -47,7 +54,11 @@ impl Format {
         self.hierarchy.iter().cloned().zip(self.count.iter().copied()).collect()
     }
 
-    pub fn to_entry_generators(self) -> Result<HashMap<String, EntryGenerator>, anyhow::Error> {
+    pub fn csv(&self) -> &CsvFormat {
+        &self.csv
+    }
+
+    pub fn into_entry_generators(self) -> Result<HashMap<String, EntryGenerator>, anyhow::Error> {
         let mut generators = HashMap::new();
         
         for (object_class, attribute_map) in self.fields.iter() {
@@ -75,8 +86,4 @@ impl Format {
 
         Ok(generators)
     }
-
-    pub fn fields(&self) -> &Fields {
-        &self.fields
-    }
 }
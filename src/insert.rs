@@ -0,0 +1,540 @@
+//! Inserts generated entries into a running server.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use clap::ValueEnum;
+use ldap3::{LdapError, LdapResult, Mod};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::checkpoint::CheckpointWriter;
+use crate::dn::split_dn;
+use crate::ldap_pool::LdapPool;
+use crate::progress::{ProgressMessage, ProgressSender};
+use crate::result_codes::ENTRY_ALREADY_EXISTS;
+use crate::retry::{with_retries, Attempts, RetryPolicy};
+use crate::sink::{EntrySink, SinkSummary};
+use crate::types::{EntrySender, InsertResult, InsertResultReceiver, LdapEntry};
+
+/// What to do with entries that already exist on the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnExists {
+    /// Count the entry as failed.
+    #[default]
+    Fail,
+    /// Leave the existing entry alone.
+    Skip,
+    /// Replace the values of all attributes of the entry, except for the object classes.
+    Replace,
+    /// Delete the existing entry and add it again. Fails for entries with children.
+    DeleteAndAdd,
+}
+
+/// Settings of the insert task.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InsertOptions {
+    pub retry: RetryPolicy,
+    pub on_exists: OnExists,
+}
+
+/// How an entry ended up on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Added,
+    Skipped,
+    Replaced,
+    Recreated,
+}
+
+/// Starts the tasks adding the received entries, one per connection of `pool`, so as many adds
+/// are in flight as there are connections. Transient errors are retried according to
+/// `options.retry`, each attempt using the next connection of the pool. The results are sent in
+/// the order the adds complete, along with the position of their entry. The current rate limit,
+/// if any, is shown next to the progress bar.
+pub fn insert_entries_task(
+    pool: LdapPool,
+    options: InsertOptions,
+    progress: ProgressSender,
+) -> (EntrySender, InsertResultReceiver) {
+    let (entry_tx, entry_rx) = mpsc::channel::<LdapEntry>(500_000);
+    let (result_tx, result_rx) = mpsc::unbounded_channel::<InsertResult>();
+
+    let pool = Arc::new(pool);
+    // the entries are numbered in the order they are received
+    let entries = Arc::new(Mutex::new((entry_rx, 0)));
+    let in_flight = Arc::new(InFlight::default());
+
+    let mut workers = JoinSet::new();
+    for _ in 0..pool.connections() {
+        let (pool, entries, in_flight, tx) = (pool.clone(), entries.clone(), in_flight.clone(), result_tx.clone());
+        workers.spawn(async move {
+            loop {
+                // the lock is released before the entry is added
+                let (position, entry) = {
+                    let (ref mut rx, ref mut received) = *entries.lock().await;
+                    let Some(entry) = rx.recv().await else { break };
+                    // the following entries wait as well, so they keep their order
+                    in_flight.start(&entry.0).await;
+                    *received += 1;
+                    (*received - 1, entry)
+                };
+
+                let dn = entry.0.clone();
+                let (retries, result) = insert_entry(&pool, &options, entry).await;
+                let result = result.map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>);
+                in_flight.finish(&dn);
+
+                if tx.send((position, retries, result)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        // the limit only changes while ramping up
+        let mut status = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                worker = workers.join_next() => match worker {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => error!("insert task failed: {e}"),
+                    None => break,
+                },
+                _ = status.tick() => {
+                    if let Some(limit) = pool.rate_limit() {
+                        drop(progress.send(ProgressMessage::Status(format!("limit {limit:.0}/s"))));
+                    }
+                }
+            }
+        }
+    });
+
+    (entry_tx, result_rx)
+}
+
+/// The DNs of the entries that are being added. An entry can only be added once its parent
+/// exists, so adding it waits until its parent is no longer in flight.
+#[derive(Debug, Default)]
+struct InFlight {
+    dns: std::sync::Mutex<HashSet<String>>,
+    finished: Notify,
+}
+
+impl InFlight {
+    /// Waits until the parent of `dn` isn't in flight anymore and marks `dn` as in flight.
+    async fn start(&self, dn: &str) {
+        let parent = split_dn(dn).1.to_ascii_lowercase();
+        loop {
+            // created before checking, so a parent finishing in between isn't missed
+            let finished = self.finished.notified();
+            {
+                let mut dns = self.dns.lock().expect("in flight mutex poisoned");
+                if !dns.contains(&parent) {
+                    dns.insert(dn.to_ascii_lowercase());
+                    return;
+                }
+            }
+            finished.await;
+        }
+    }
+
+    fn finish(&self, dn: &str) {
+        self.dns.lock().expect("in flight mutex poisoned").remove(&dn.to_ascii_lowercase());
+        self.finished.notify_waiters();
+    }
+}
+
+/// Adds `entry`, handling an existing entry as configured. Returns the total number of
+/// retries of all operations along with the outcome.
+async fn insert_entry(pool: &LdapPool, options: &InsertOptions, entry: LdapEntry) -> (u32, Result<InsertOutcome, LdapError>) {
+    let (dn, attributes) = &entry;
+    let (attempts, result) = with_retries(&options.retry, || add(pool, dn, attributes)).await;
+    let mut retries = attempts.retries;
+
+    if !already_exists(&result) {
+        return (retries, result.map(|_| InsertOutcome::Added));
+    }
+
+    if added_by_lost_attempt(&attempts, &result) {
+        return (retries, Ok(InsertOutcome::Added));
+    }
+
+    let result = match options.on_exists {
+        OnExists::Fail => result.map(|_| InsertOutcome::Added),
+        OnExists::Skip => Ok(InsertOutcome::Skipped),
+        OnExists::Replace => {
+            let (modify_retries, result) = with_retries(&options.retry, || {
+                let mods = replace_mods(attributes);
+                async move { pool.get_write_conn().await.modify(dn, mods).await.and_then(|res| res.success()) }
+            })
+            .await;
+            retries += modify_retries.retries;
+            result.map(|_| InsertOutcome::Replaced)
+        }
+        OnExists::DeleteAndAdd => {
+            let (delete_retries, result) = with_retries(&options.retry, || async move {
+                pool.get_write_conn().await.delete(dn).await.and_then(|res| res.success())
+            })
+            .await;
+            retries += delete_retries.retries;
+
+            match result {
+                Ok(_) => {
+                    let (add_retries, result) = with_retries(&options.retry, || add(pool, dn, attributes)).await;
+                    retries += add_retries.retries;
+                    result.map(|_| InsertOutcome::Recreated)
+                }
+                Err(e) => Err(e),
+            }
+        }
+    };
+
+    (retries, result)
+}
+
+/// Whether adding an entry failed because it already exists.
+fn already_exists(result: &Result<LdapResult, LdapError>) -> bool {
+    matches!(result, Err(LdapError::LdapResult { ref result }) if result.rc == ENTRY_ALREADY_EXISTS)
+}
+
+/// Whether an entry reported as existing was added by an earlier attempt, whose response got
+/// lost because the operation timed out or the connection dropped. The entry is ours then, so it
+/// counts as added, whatever the `OnExists` policy. Attempts refused by the server, e.g. as it
+/// was busy, haven't added anything.
+fn added_by_lost_attempt(attempts: &Attempts, result: &Result<LdapResult, LdapError>) -> bool {
+    attempts.lost_response && already_exists(result)
+}
+
+/// Adds the entry using the next connection of `pool`, once the rate limits allow it.
+pub fn add<'a>(
+    pool: &'a LdapPool,
+    dn: &'a str,
+    attributes: &[(String, HashSet<String>)],
+) -> impl Future<Output = Result<LdapResult, LdapError>> + 'a {
+    let attributes = attributes.to_vec();
+    async move {
+        let mut conn = pool.get_write_conn().await;
+        // `add` only fails on protocol errors, the result code has to be checked separately
+        conn.add(dn, attributes).await.and_then(|res| res.success())
+    }
+}
+
+/// Replaces every attribute but the object classes, which usually can't be changed.
+fn replace_mods(attributes: &[(String, HashSet<String>)]) -> Vec<Mod<String>> {
+    attributes
+        .iter()
+        .filter(|(k, _)| !k.eq_ignore_ascii_case("objectclass"))
+        .map(|(k, v)| Mod::Replace(k.clone(), v.clone()))
+        .collect()
+}
+
+/// Sink that adds all received entries to the server. Every processed entry advances the
+/// progress bar, failed inserts are printed above it.
+pub struct InsertSink {
+    name: String,
+    server: String,
+    sender: EntrySender,
+    result_task: JoinHandle<InsertCounts>,
+}
+
+/// The number of entries per outcome, and of entries that needed retries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InsertCounts {
+    pub added: u64,
+    pub skipped: u64,
+    pub replaced: u64,
+    pub recreated: u64,
+    pub failed: u64,
+    pub retried: u64,
+}
+
+impl InsertCounts {
+    /// Counts an entry that needed `retries` retries and ended up as `outcome`, `None` if it
+    /// couldn't be inserted.
+    pub fn record(&mut self, retries: u32, outcome: Option<InsertOutcome>) {
+        if retries > 0 {
+            self.retried += 1;
+        }
+
+        let count = match outcome {
+            Some(InsertOutcome::Added) => &mut self.added,
+            Some(InsertOutcome::Skipped) => &mut self.skipped,
+            Some(InsertOutcome::Replaced) => &mut self.replaced,
+            Some(InsertOutcome::Recreated) => &mut self.recreated,
+            None => &mut self.failed,
+        };
+        *count += 1;
+    }
+}
+
+impl InsertSink {
+    /// Creates the sink. If `checkpoint` is given, the counters continue from it and the
+    /// progress is saved to its state file.
+    pub fn new(
+        pool: LdapPool,
+        server: &str,
+        options: InsertOptions,
+        mut checkpoint: Option<CheckpointWriter>,
+        progress: ProgressSender,
+    ) -> Self {
+        let (sender, result_receiver) = insert_entries_task(pool, options, progress.clone());
+
+        // forward the insert results to the progress bar and count them
+        let result_task = tokio::spawn(async move {
+            let mut result_stream = UnboundedReceiverStream::new(result_receiver);
+            let mut counts = checkpoint.as_ref().map(CheckpointWriter::counts).unwrap_or_default();
+
+            while let Some((position, retries, res)) = result_stream.next().await {
+                let outcome = res.as_ref().ok().copied();
+                counts.record(retries, outcome);
+
+                let message = match res {
+                    Ok(_) => ProgressMessage::Progress,
+                    Err(e) => ProgressMessage::ProgressWithMessage(format!("Error: {e}")),
+                };
+                drop(progress.send(message));
+
+                if let Some(ref mut checkpoint) = checkpoint {
+                    checkpoint.update(position, retries, outcome).await;
+                }
+            }
+
+            if let Some(checkpoint) = checkpoint {
+                if let Err(e) = checkpoint.finish().await {
+                    error!("{e:#}");
+                }
+            }
+
+            counts
+        });
+
+        Self {
+            name: format!("insert into {server}"),
+            server: server.to_owned(),
+            sender,
+            result_task,
+        }
+    }
+}
+
+#[async_trait]
+impl EntrySink for InsertSink {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn reports_progress(&self) -> bool {
+        true
+    }
+
+    async fn send(&mut self, entry: LdapEntry) -> anyhow::Result<()> {
+        self.sender
+            .send(entry)
+            .await
+            .map_err(|_| anyhow!("{} stopped unexpectedly", self.name))
+    }
+
+    async fn close(self: Box<Self>) -> anyhow::Result<SinkSummary> {
+        let InsertSink { server, sender, result_task, .. } = *self;
+        drop(sender);
+
+        // the result channel is closed once the insert task has processed all entries
+        let counts = result_task.await?;
+
+        let mut summary = vec![(server.clone(), counts.added)];
+        // existing entries are only handled differently if requested
+        for (outcome, count) in [("skipped", counts.skipped), ("replaced", counts.replaced), ("recreated", counts.recreated)] {
+            if count > 0 {
+                summary.push((format!("{server} ({outcome})"), count));
+            }
+        }
+        summary.push((format!("{server} (failed)"), counts.failed));
+        summary.push((format!("{server} (retried)"), counts.retried));
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::LdapConfig;
+
+    #[test]
+    fn test_replace_mods() {
+        let values = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<HashSet<_>>();
+        let attributes = vec![
+            ("objectClass".to_string(), values(&["top", "person"])),
+            ("cn".to_string(), values(&["Jane Doe"])),
+            ("mail".to_string(), values(&["jane@example.org", "jdoe@example.org"])),
+        ];
+
+        let mods = replace_mods(&attributes);
+        assert_eq!(mods.len(), 2);
+        assert!(mods.iter().all(|m| matches!(m, Mod::Replace(k, _) if !k.eq_ignore_ascii_case("objectclass"))));
+        assert!(matches!(&mods[1], Mod::Replace(k, v) if k == "mail" && v.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn test_added_by_lost_attempt() {
+        use crate::retry::result_error;
+
+        let policy = RetryPolicy { max_retries: 3, initial_backoff: 1, max_backoff: 1 };
+        let add_failing_with = |first: LdapError| {
+            let mut first = Some(first);
+            with_retries(&policy, move || {
+                let error = first.take().unwrap_or_else(|| result_error(ENTRY_ALREADY_EXISTS));
+                async move { Err::<LdapResult, _>(error) }
+            })
+        };
+
+        // the connection dropped before the response arrived, but the server added the entry
+        let dropped = LdapError::Io { source: std::io::ErrorKind::ConnectionReset.into() };
+        let (attempts, result) = add_failing_with(dropped).await;
+        assert_eq!(attempts.retries, 1);
+        assert!(added_by_lost_attempt(&attempts, &result));
+
+        // the server was busy and didn't add the entry, so it existed before
+        let (attempts, result) = add_failing_with(result_error(crate::result_codes::BUSY)).await;
+        assert_eq!(attempts.retries, 1);
+        assert!(already_exists(&result) && !added_by_lost_attempt(&attempts, &result));
+
+        // the entry existed before the first attempt
+        let result = Err(result_error(ENTRY_ALREADY_EXISTS));
+        assert!(already_exists(&result) && !added_by_lost_attempt(&Attempts::default(), &result));
+    }
+
+    /// Starts a server answering add requests after `delay`. Entries below `dc=example,dc=org`
+    /// are added if their parent exists, like a real server. The requests of a connection are
+    /// answered one after the other. Returns the URL of the server.
+    async fn start_add_server(delay: Duration) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let added = Arc::new(std::sync::Mutex::new(HashSet::from(["dc=example,dc=org".to_string()])));
+
+        // reads the length of a BER element, returning it along with the number of bytes read
+        fn length(bytes: &[u8]) -> (usize, usize) {
+            match bytes[0] as usize {
+                len if len & 0x80 == 0 => (len, 1),
+                len => {
+                    let n = len & 0x7f;
+                    (bytes[1..=n].iter().fold(0, |len, b| len << 8 | *b as usize), n + 1)
+                }
+            }
+        }
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let added = added.clone();
+                tokio::spawn(async move {
+                    // LDAPMessage ::= SEQUENCE { messageID INTEGER, protocolOp, ... }
+                    let mut header = [0; 2];
+                    while socket.read_exact(&mut header).await.is_ok() {
+                        let mut len = header[1] as usize;
+                        if len & 0x80 != 0 {
+                            let mut bytes = vec![0; len & 0x7f];
+                            socket.read_exact(&mut bytes).await.unwrap();
+                            len = bytes.iter().fold(0, |len, b| len << 8 | *b as usize);
+                        }
+                        let mut message = vec![0; len];
+                        socket.read_exact(&mut message).await.unwrap();
+
+                        let id = &message[..2 + message[1] as usize];
+                        // AddRequest ::= [APPLICATION 8] SEQUENCE { entry LDAPDN, ... }, the
+                        // UnbindRequest is ignored
+                        if message[id.len()] != 0x68 {
+                            continue;
+                        }
+                        let start = id.len() + 1 + length(&message[id.len() + 1..]).1;
+                        let (dn_len, read) = length(&message[start + 1..]);
+                        let dn = std::str::from_utf8(&message[start + 1 + read..][..dn_len]).unwrap();
+
+                        // the entry only exists once the add has been processed
+                        let parent_exists = added.lock().unwrap().contains(split_dn(dn).1);
+                        tokio::time::sleep(delay).await;
+                        let rc = if parent_exists {
+                            added.lock().unwrap().insert(dn.to_string());
+                            0
+                        } else {
+                            crate::result_codes::NO_SUCH_OBJECT as u8
+                        };
+
+                        // AddResponse ::= [APPLICATION 9] LDAPResult
+                        let response = [0x69, 0x07, 0x0a, 0x01, rc, 0x04, 0x00, 0x04, 0x00];
+                        let mut reply = vec![0x30, (id.len() + response.len()) as u8];
+                        reply.extend_from_slice(id);
+                        reply.extend_from_slice(&response);
+                        if socket.write_all(&reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        format!("ldap://127.0.0.1:{port}")
+    }
+
+    /// Inserts `dns` in order using a pool configured by `config`, checking that all of them are
+    /// added, and returns how long that took.
+    async fn insert(config: &str, dns: &[String]) -> Duration {
+        let pool = LdapPool::new(toml::from_str::<LdapConfig>(config).unwrap()).await.unwrap();
+        let (progress, _) = mpsc::unbounded_channel();
+        let (entries, mut results) = insert_entries_task(pool, InsertOptions::default(), progress);
+
+        let start = std::time::Instant::now();
+        for dn in dns {
+            entries.send((dn.clone(), vec![])).await.unwrap();
+        }
+        drop(entries);
+
+        let mut positions = vec![];
+        while let Some((position, _, result)) = results.recv().await {
+            assert_eq!(result.unwrap(), InsertOutcome::Added, "entry {position}");
+            positions.push(position);
+        }
+        positions.sort_unstable();
+        assert_eq!(positions, (0..dns.len() as u64).collect::<Vec<_>>());
+
+        start.elapsed()
+    }
+
+    #[tokio::test]
+    async fn test_parallel_inserts() {
+        let users = |ou| (0..8).map(move |i| format!("uid={i},ou={ou},dc=example,dc=org"));
+        let dns: Vec<String> = ["ou=a,dc=example,dc=org".to_string(), "ou=b,dc=example,dc=org".to_string()]
+            .into_iter()
+            .chain(users("a"))
+            .chain(users("b"))
+            .collect();
+
+        // one add after the other would take 18 * 50ms, the users wait for their parents
+        let server = start_add_server(Duration::from_millis(50)).await;
+        let elapsed = insert(&format!("server = \"{server}\"\nconnections = 4\n"), &dns).await;
+        assert!(elapsed < Duration::from_millis(600), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_rate_per_connection() {
+        let server = start_add_server(Duration::ZERO).await;
+        let dns: Vec<String> = (0..40).map(|i| format!("uid={i},dc=example,dc=org")).collect();
+        let config = format!("server = \"{server}\"\nconnections = 4\n\n[rate-limit]\nper-connection = 20\n");
+
+        // 10 entries per connection, the first one without waiting: 9 / 20 ops/s = 450ms, while
+        // a single connection at 20 ops/s would need 2s
+        let elapsed = insert(&config, &dns).await;
+        assert!(elapsed >= Duration::from_millis(400), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1000), "{elapsed:?}");
+    }
+}
@@ -0,0 +1,153 @@
+//! Exports generated entries as JSON Lines, one JSON object per entry. This allows consuming
+//! the generated data without an LDIF parser.
+//!
+//! Every line has the form `{"dn": "...", "attributes": {"cn": ["..."]}}`. Attribute values
+//! are always written as arrays to support multi-valued attributes. Exported files can be read
+//! again, e.g. to verify the entries on a server.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::io as tio;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::compression::{self, Compression};
+use crate::reader::EntryParser;
+use crate::sink::{ChannelSink, SinkSummary};
+use crate::types::LdapEntry;
+
+pub type JsonlReceiver = UnboundedReceiver<LdapEntry>;
+
+/// Serializable representation of a single entry.
+#[derive(Debug, Serialize)]
+struct JsonEntry<'e> {
+    dn: &'e str,
+    attributes: BTreeMap<&'e str, Vec<&'e str>>,
+}
+
+/// An entry read from a JSON Lines file.
+#[derive(Debug, Deserialize)]
+struct OwnedJsonEntry {
+    dn: String,
+    attributes: BTreeMap<String, Vec<String>>,
+}
+
+pub async fn start_jsonl_export_task<P: AsRef<Path>>(export_file: P, compression: Compression) -> anyhow::Result<ChannelSink> {
+    let (tx, rx) = unbounded_channel();
+    let destination = compression::display_name(export_file.as_ref());
+    let name = format!("JSONL export to {destination}");
+
+    let writer = compression::create_async(export_file.as_ref(), compression).await?;
+
+    let task = tokio::spawn(async move { jsonl_exporter(rx, writer, destination).await });
+
+    Ok(ChannelSink::new(name, tx, task))
+}
+
+/// Writes all received entries to `writer` and flushes it once the channel has been closed.
+/// Stops at the first error.
+async fn jsonl_exporter<O: tio::AsyncWriteExt + Unpin>(rx: JsonlReceiver, mut writer: O, destination: String) -> anyhow::Result<SinkSummary> {
+    let mut stream = UnboundedReceiverStream::new(rx);
+    let mut count = 0;
+    while let Some(entry) = stream.next().await {
+        let line = build_entry_line(&entry)
+            .with_context(|| format!("failed to serialize entry {}", entry.0))?;
+
+        writer.write_all(line.as_bytes()).await
+            .with_context(|| format!("failed to write entry to {destination}"))?;
+        count += 1;
+    }
+
+    writer.shutdown().await.with_context(|| format!("failed to flush {destination}"))?;
+
+    Ok(vec![(destination, count)])
+}
+
+/// Serializes `entry` into a single, newline-terminated JSON line. Values of multi-valued
+/// attributes are sorted to produce stable output.
+fn build_entry_line(entry: &LdapEntry) -> serde_json::Result<String> {
+    let (dn, attributes) = entry;
+    let mut json_entry = JsonEntry {
+        dn: dn.as_str(),
+        attributes: BTreeMap::new(),
+    };
+
+    for (key, values) in attributes.iter() {
+        let json_values = json_entry.attributes.entry(key.as_str()).or_default();
+        json_values.extend(values.iter().map(String::as_str));
+        json_values.sort_unstable();
+    }
+
+    let mut line = serde_json::to_string(&json_entry)?;
+    line.push('\n');
+
+    Ok(line)
+}
+
+/// Parses JSON Lines files written by the exporter. Blank lines are skipped.
+#[derive(Debug, Default)]
+pub struct JsonlParser {
+    line_number: usize,
+}
+
+impl EntryParser for JsonlParser {
+    const FORMAT: &'static str = "JSON";
+
+    fn feed_line(&mut self, line: &str) -> anyhow::Result<Option<LdapEntry>> {
+        self.line_number += 1;
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let entry = parse_entry_line(line).with_context(|| format!("line {}", self.line_number))?;
+        Ok(Some(entry))
+    }
+
+    fn finish(self) -> anyhow::Result<Option<LdapEntry>> {
+        Ok(None)
+    }
+}
+
+/// Parses a line written by `build_entry_line`.
+fn parse_entry_line(line: &str) -> serde_json::Result<LdapEntry> {
+    let entry: OwnedJsonEntry = serde_json::from_str(line)?;
+    let attributes = entry
+        .attributes
+        .into_iter()
+        .map(|(key, values)| (key, values.into_iter().collect::<HashSet<_>>()))
+        .collect();
+
+    Ok((entry.dn, attributes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_jsonl() {
+        let entry = (
+            "uid=test.user,ou=users,dc=example,dc=org".to_string(),
+            vec![
+            ("objectClass".to_string(), HashSet::from(["inetOrgPerson".to_string()])),
+            ("uid".to_string(), HashSet::from(["test.user".to_string()])),
+            ("mail".to_string(), HashSet::from(["b@example.org".to_string(), "a@example.org".to_string()]))
+            ]
+        );
+
+        let line = build_entry_line(&entry).expect("valid entry");
+
+        assert_eq!(line.as_str(), "{\"dn\":\"uid=test.user,ou=users,dc=example,dc=org\",\"attributes\":{\"mail\":[\"a@example.org\",\"b@example.org\"],\"objectClass\":[\"inetOrgPerson\"],\"uid\":[\"test.user\"]}}\n");
+
+        let (dn, mut attributes) = parse_entry_line(&line).expect("valid line");
+        attributes.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(dn, entry.0);
+        assert_eq!(attributes[0], entry.1[2]);
+        assert_eq!(attributes[1], entry.1[0]);
+        assert!(parse_entry_line("{\"dn\": 1}").is_err());
+    }
+}
@@ -1,44 +1,376 @@
 //! A simple connection pool for ldap connections.
-use ldap3::{Ldap, LdapConnAsync, LdapError};
-use std::sync::atomic::{AtomicUsize, Ordering};
+//!
+//! Connections that have been closed, e.g. because the server restarted or an idle timeout
+//! expired, are taken out of rotation. They are reconnected and bound again in the background
+//! and used again once that succeeded.
+//!
+//! Operations can be rate limited over all connections and per connection, see `crate::rate`.
+//! The configured timeout applies to every operation, the controls to every write operation.
+use anyhow::Context;
+use ldap3::controls::RawControl;
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings};
+use native_tls::{Certificate, Identity, TlsConnector};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
-use crate::config::LdapConfig;
+use crate::config::{BindMode, LdapConfig, TlsConfig};
+use crate::rate::RateLimiter;
 
 #[derive(Debug)]
 pub struct LdapPool {
-    conns: Vec<Ldap>,
+    slots: Vec<Arc<Slot>>,
     index: AtomicUsize,
+    settings: Arc<PoolSettings>,
+    limiter: Option<RateLimiter>,
+}
+
+/// Everything needed to open another connection, and the settings of its operations.
+struct PoolSettings {
+    config: LdapConfig,
+    conn_settings: LdapConnSettings,
+    controls: Vec<RawControl>,
+}
+
+impl std::fmt::Debug for PoolSettings {
+    // `LdapConnSettings` doesn't implement `Debug`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PoolSettings").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+/// A pooled connection. While `healthy` is false, the connection is being re-established.
+#[derive(Debug)]
+struct Slot {
+    conn: Mutex<Ldap>,
+    healthy: AtomicBool,
+    limiter: Option<RateLimiter>,
 }
 
 impl LdapPool {
     /// Creates a new pool with `count` connections using `settings`. Each connection
-    /// will be driven immediately and a bind operations with the provided credentials
-    /// is performed. If any of the binds or connects fails, the function returns an
-    /// error.
+    /// will be driven immediately and authenticated as configured by the bind mode. If any
+    /// of the binds or connects fails, the function returns an error.
     ///
     /// # Panics
     /// Panics if `count` cannot be allocated by `Vec`.
-    pub async fn new(settings: LdapConfig) -> Result<Self, LdapError> {
-        let mut conns = Vec::with_capacity(settings.connections());
+    pub async fn new(settings: LdapConfig) -> anyhow::Result<Self> {
+        let mut slots = Vec::with_capacity(settings.connections());
         let index = AtomicUsize::new(0);
+        let conn_settings = conn_settings(settings.tls())?;
+        check_bind(&settings)?;
+        let controls = settings.controls()?;
+        let settings = Arc::new(PoolSettings { config: settings, conn_settings, controls });
+        let rate_limit = settings.config.rate_limit();
+        let ramp_up = Duration::from_secs(rate_limit.ramp_up);
 
-        for _ in 0..settings.connections() {
-            let (conn, mut ldap) = LdapConnAsync::new(settings.server()).await?;
-            ldap3::drive!(conn);
-
-            ldap.simple_bind(settings.user(), settings.password())
-                .await?;
-
-            conns.push(ldap);
+        for _ in 0..settings.config.connections() {
+            let ldap = connect(&settings).await?;
+            let limiter = rate_limit.per_connection.map(|rate| RateLimiter::new(rate, ramp_up));
+            slots.push(Arc::new(Slot { conn: Mutex::new(ldap), healthy: AtomicBool::new(true), limiter }));
         }
 
-        Ok(LdapPool { conns, index })
+        let limiter = rate_limit.ops_per_second.map(|rate| RateLimiter::new(rate, ramp_up));
+
+        Ok(LdapPool { slots, index, settings, limiter })
     }
 
     /// Rotates the internal queue and returns a cloned reference to one of the
-    /// available connections. The connections are shared using round-robin.
+    /// available connections. The connections are shared using round-robin, closed
+    /// connections are skipped and reconnected in the background. If no connection is
+    /// available, a closed one is returned, so the operation fails and can be retried.
+    ///
+    /// The timeout is applied to the next operation of the returned connection.
     pub fn get_conn(&self) -> Ldap {
-        let index = self.index.fetch_add(1, Ordering::SeqCst) % self.conns.len();
-        self.conns[index].clone()
+        let mut conn = self.next_conn().1;
+        self.with_timeout(&mut conn);
+
+        conn
+    }
+
+    /// Like `get_conn`, but waits until the rate limits allow another operation and attaches
+    /// the configured controls. Used for adds, modifies and deletes.
+    pub async fn get_write_conn(&self) -> Ldap {
+        if let Some(ref limiter) = self.limiter {
+            limiter.acquire().await;
+        }
+
+        let (slot, mut conn) = self.next_conn();
+        if let Some(ref limiter) = slot.limiter {
+            limiter.acquire().await;
+        }
+
+        self.with_timeout(&mut conn);
+        if !self.settings.controls.is_empty() {
+            conn.with_controls(self.settings.controls.clone());
+        }
+
+        conn
+    }
+
+    /// The controls attached to every write operation.
+    pub fn controls(&self) -> &[RawControl] {
+        &self.settings.controls
+    }
+
+    /// The number of connections of the pool, usable or not.
+    pub fn connections(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn with_timeout(&self, conn: &mut Ldap) {
+        if let Some(timeout) = self.settings.config.timeout() {
+            conn.with_timeout(timeout);
+        }
+    }
+
+    /// The current limit of operations per second over all connections, if any.
+    pub fn rate_limit(&self) -> Option<f64> {
+        let global = self.limiter.as_ref().map(RateLimiter::rate);
+        let per_connection = self
+            .slots
+            .iter()
+            .map(|s| s.limiter.as_ref().map(RateLimiter::rate))
+            .sum::<Option<f64>>();
+
+        match (global, per_connection) {
+            (Some(global), Some(per_connection)) => Some(global.min(per_connection)),
+            (global, per_connection) => global.or(per_connection),
+        }
+    }
+
+    /// Returns the next usable connection along with its slot.
+    fn next_conn(&self) -> (&Slot, Ldap) {
+        let start = self.index.fetch_add(1, Ordering::SeqCst);
+
+        for offset in 0..self.slots.len() {
+            let slot = &self.slots[(start + offset) % self.slots.len()];
+            if !slot.healthy.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            let mut conn = slot.conn.lock().expect("pool mutex poisoned").clone();
+            if !conn.is_closed() {
+                return (slot, conn);
+            }
+
+            // only the first caller noticing the closed connection starts reconnecting
+            if slot.healthy.swap(false, Ordering::SeqCst) {
+                warn!(
+                    "connection to {} lost, reconnecting ({} of {} connections available)",
+                    self.settings.config.server(),
+                    self.healthy_connections(),
+                    self.slots.len()
+                );
+                tokio::spawn(reconnect(Arc::downgrade(slot), self.settings.clone()));
+            }
+        }
+
+        let slot = &self.slots[start % self.slots.len()];
+        (slot, slot.conn.lock().expect("pool mutex poisoned").clone())
+    }
+
+    /// The number of connections that are currently usable.
+    pub fn healthy_connections(&self) -> usize {
+        self.slots.iter().filter(|s| s.healthy.load(Ordering::SeqCst)).count()
+    }
+}
+
+/// Opens and binds a new connection.
+async fn connect(settings: &PoolSettings) -> anyhow::Result<Ldap> {
+    let server = settings.config.server();
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings.conn_settings.clone(), server)
+        .await
+        .with_context(|| format!("failed to connect to {server}"))?;
+    ldap3::drive!(conn);
+
+    bind(&mut ldap, &settings.config)
+        .await
+        .with_context(|| format!("failed to bind to {server}"))?;
+
+    Ok(ldap)
+}
+
+/// Reconnects the connection of `slot` until it succeeds, waiting longer after every failed
+/// attempt. Stops if the pool has been dropped in the meantime.
+async fn reconnect(slot: Weak<Slot>, settings: Arc<PoolSettings>) {
+    let retry = settings.config.retry();
+    let mut attempt = 0;
+
+    loop {
+        tokio::time::sleep(retry.backoff(attempt)).await;
+        if slot.strong_count() == 0 {
+            return;
+        }
+
+        match connect(&settings).await {
+            Ok(ldap) => {
+                let Some(slot) = slot.upgrade() else { return };
+                *slot.conn.lock().expect("pool mutex poisoned") = ldap;
+                slot.healthy.store(true, Ordering::SeqCst);
+                info!("reconnected to {}", settings.config.server());
+                return;
+            }
+            Err(e) => {
+                debug!("reconnect attempt {} failed: {e:#}", attempt + 1);
+                attempt = attempt.saturating_add(1);
+            }
+        }
+    }
+}
+
+/// Checks that the bind mode can be used with the other settings before connecting.
+fn check_bind(settings: &LdapConfig) -> anyhow::Result<()> {
+    match settings.bind() {
+        BindMode::Simple if settings.user().is_none() => bail!("a simple bind requires a user"),
+        BindMode::SaslExternal
+            if !settings.server().starts_with("ldapi://") && settings.tls().client_cert.is_none() =>
+        {
+            bail!("SASL EXTERNAL requires an ldapi:// server or a TLS client certificate")
+        }
+        BindMode::Anonymous if settings.user().is_some() => {
+            warn!("connecting anonymously, the user is ignored");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Authenticates `ldap` as configured. Anonymous connections don't need a bind at all.
+async fn bind(ldap: &mut Ldap, settings: &LdapConfig) -> anyhow::Result<()> {
+    if settings.bind() == BindMode::Anonymous {
+        return Ok(());
+    }
+
+    if let Some(timeout) = settings.timeout() {
+        ldap.with_timeout(timeout);
+    }
+
+    let result = match settings.bind() {
+        BindMode::SaslExternal => ldap.sasl_external_bind().await?,
+        _ => ldap.simple_bind(settings.user().unwrap_or_default(), settings.password()).await?,
+    };
+
+    // the result code of the bind has to be checked separately
+    result.success()?;
+
+    Ok(())
+}
+
+/// Builds the connection settings for `tls`. The default connector is replaced only if
+/// certificates have to be loaded or verification is disabled.
+fn conn_settings(tls: &TlsConfig) -> anyhow::Result<LdapConnSettings> {
+    let settings = LdapConnSettings::new().set_starttls(tls.starttls);
+
+    if tls.ca_file.is_none() && tls.client_cert.is_none() && !tls.insecure {
+        return Ok(settings);
+    }
+
+    let mut builder = TlsConnector::builder();
+
+    if let Some(ref ca_file) = tls.ca_file {
+        let pem = std::fs::read(ca_file).with_context(|| format!("failed to read CA file {ca_file}"))?;
+        let certificate = Certificate::from_pem(&pem).with_context(|| format!("invalid CA certificate in {ca_file}"))?;
+        builder.add_root_certificate(certificate);
+    }
+
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert_file), Some(key_file)) => {
+            let cert = std::fs::read(cert_file).with_context(|| format!("failed to read client certificate {cert_file}"))?;
+            let key = std::fs::read(key_file).with_context(|| format!("failed to read client key {key_file}"))?;
+            let identity = Identity::from_pkcs8(&cert, &key).context("invalid client certificate or key")?;
+            builder.identity(identity);
+        }
+        (None, None) => (),
+        _ => bail!("client certificate and key must be specified together"),
+    }
+
+    if tls.insecure {
+        warn!("TLS certificate verification is disabled");
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    }
+
+    let connector = builder.build().context("failed to set up TLS")?;
+
+    Ok(settings.set_connector(connector))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tls_config() {
+        let config: LdapConfig = toml::from_str(
+            "server = \"ldap://localhost\"\nuser = \"cn=admin\"\nconnections = 1\n\n[tls]\nstarttls = true\nca-file = \"/nonexistent/ca.pem\"\n",
+        )
+        .unwrap();
+
+        assert!(config.tls().starttls);
+        assert!(conn_settings(config.tls()).is_err());
+        assert!(conn_settings(&TlsConfig { starttls: true, ..Default::default() }).unwrap().starttls());
+        assert!(conn_settings(&TlsConfig { insecure: true, ..Default::default() }).is_ok());
+        assert!(conn_settings(&TlsConfig { client_cert: Some("cert.pem".to_string()), ..Default::default() }).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        use tokio::net::TcpListener;
+
+        // anonymous connections don't send any requests, so a plain socket is enough
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config: LdapConfig = toml::from_str(&format!(
+            "server = \"ldap://127.0.0.1:{port}\"\nconnections = 2\n\n[retry]\ninitial-backoff = 1\nmax-backoff = 10\n"
+        ))
+        .unwrap();
+
+        // connecting succeeds as soon as the connection is in the backlog of the listener
+        let pool = LdapPool::new(config).await.unwrap();
+        let (first, _) = listener.accept().await.unwrap();
+        let _second = listener.accept().await.unwrap();
+        assert_eq!(pool.healthy_connections(), 2);
+
+        // the server closes the first connection, which the pool notices when handing out
+        // connections. The reconnect task can't run before the next await of this test.
+        drop(first);
+        let noticed = async {
+            loop {
+                assert!(!pool.get_conn().is_closed());
+                if pool.healthy_connections() == 1 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), noticed).await.expect("closed connection not noticed");
+
+        // the connection is re-established in the background
+        let reconnected = async {
+            let third = listener.accept().await.unwrap();
+            while pool.healthy_connections() < 2 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            third
+        };
+        let _third = tokio::time::timeout(Duration::from_secs(5), reconnected).await.expect("connection not re-established");
+    }
+
+    #[test]
+    fn test_bind_mode() {
+        let config = |s: &str| -> LdapConfig { toml::from_str(&format!("connections = 1\n{s}")).unwrap() };
+
+        let simple = config("server = \"ldap://localhost\"\nuser = \"cn=admin\"");
+        assert_eq!(simple.bind(), BindMode::Simple);
+        assert!(check_bind(&simple).is_ok());
+
+        let anonymous = config("server = \"ldap://localhost\"");
+        assert_eq!(anonymous.bind(), BindMode::Anonymous);
+        assert!(check_bind(&anonymous).is_ok());
+
+        assert!(check_bind(&config("server = \"ldap://localhost\"\nbind = \"simple\"")).is_err());
+        assert!(check_bind(&config("server = \"ldapi:///\"\nbind = \"sasl-external\"")).is_ok());
+        assert!(check_bind(&config("server = \"ldap://localhost\"\nbind = \"sasl-external\"")).is_err());
     }
 }
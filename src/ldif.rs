@@ -1,64 +1,264 @@
-/// Exports generated entries as LDIF file instead of directly adding them to the server.
-/// This increases reusability at the cost of possibly generating invalid entries as there 
-/// is no syntax validation according to the ldif specification. (And I don't have time to 
-/// read all that and test it in less than 5 weeks)
+//! Exports generated entries as LDIF file instead of directly adding them to the server.
+//! This increases reusability at the cost of possibly generating invalid entries as there 
+//! is no syntax validation according to the ldif specification. (And I don't have time to 
+//! read all that and test it in less than 5 weeks)
+
+pub mod changes;
+pub mod parser;
 
 use std::path::{Path, PathBuf};
-use std::io;
+use std::str::FromStr;
 
-use tokio::fs as tfs;
-use tokio::io as tio;
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::unbounded_channel;
 use tokio_stream::StreamExt;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 
-use crate::types::{LdifSender, LdifReceiver, LdapEntry};
+use crate::compression::{self, AsyncOutput, Compression};
+use crate::sink::{ChannelSink, SinkSummary};
+use crate::types::{LdifReceiver, LdapEntry};
+
+const CHANGETYPE_ADD: &str = "changetype: add\n";
+
+/// Limits the size of a single LDIF file. Once the limit would be exceeded, the export
+/// continues in the next file. Files are named `<name>-0001.ldif`, `<name>-0002.ldif` etc.
+///
+/// Entries are written in the order they are generated, i.e. parents always come before their
+/// children. Therefore, every chunk only references parents contained in itself or in one of
+/// the previous chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkLimit {
+    /// The maximum number of entries per file.
+    Entries(u64),
+    /// The maximum (uncompressed) size per file. A single entry exceeding the size is written
+    /// into its own file.
+    Size(ByteSize),
+}
+
+/// A size in bytes, which can be specified with a binary unit suffix: `64K`, `100M`, `2G`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "SizeValue")]
+pub struct ByteSize(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeValue {
+    Bytes(u64),
+    String(String),
+}
+
+impl TryFrom<SizeValue> for ByteSize {
+    type Error = anyhow::Error;
+
+    fn try_from(value: SizeValue) -> Result<Self, Self::Error> {
+        match value {
+            SizeValue::Bytes(bytes) => Ok(ByteSize(bytes)),
+            SizeValue::String(s) => s.parse(),
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, factor) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 1 << 10),
+            Some('M') => (&s[..s.len() - 1], 1 << 20),
+            Some('G') => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+
+        let number: u64 = number.trim().parse().with_context(|| format!("invalid size: {s}"))?;
+        if number == 0 {
+            bail!("size must be greater than 0");
+        }
 
-pub async fn start_ldif_export_task<P: AsRef<Path>>(export_file: P) -> anyhow::Result<LdifSender> {
+        Ok(ByteSize(number * factor))
+    }
+}
+
+/// Starts the LDIF export. If `change_records` is set, entries are written as `changetype: add`
+/// change records.
+pub async fn start_ldif_export_task<P: AsRef<Path>>(export_file: P, compression: Compression, limit: Option<ChunkLimit>, change_records: bool) -> anyhow::Result<ChannelSink> {
     let (tx, rx) = unbounded_channel();
+    let name = format!("LDIF export to {}", compression::display_name(export_file.as_ref()));
+
+    if limit.is_some() && compression::is_stdout(export_file.as_ref()) {
+        bail!("cannot split LDIF output written to stdout");
+    }
 
-    let file = tfs::File::create(export_file).await?;
-    let writer = tio::BufWriter::new(file);
+    let output = LdifOutput::create(export_file.as_ref().to_path_buf(), compression, limit).await?;
 
-    tokio::spawn(async move { ldif_exporter(rx, writer).await });
+    let task = tokio::spawn(async move { ldif_exporter(rx, output, change_records).await });
 
-    Ok(tx)
+    Ok(ChannelSink::new(name, tx, task))
 }
 
-async fn ldif_exporter<O: tio::AsyncWriteExt + Unpin>(rx: LdifReceiver, mut writer: O) {
+/// Writes all received entries to `output` and flushes it once the channel has been closed.
+/// Stops at the first write error.
+async fn ldif_exporter(rx: LdifReceiver, mut output: LdifOutput, change_records: bool) -> anyhow::Result<SinkSummary> {
     let mut stream = UnboundedReceiverStream::new(rx);
     while let Some(entry) = stream.next().await {
-        let entry_string = build_entry_string(entry);
+        let entry_string = build_entry_string(entry, change_records);
+
+        output.write_entry(entry_string.as_bytes()).await?;
+    }
+
+    output.finish().await
+}
+
+/// The file(s) the LDIF export writes to. Takes care of starting a new file whenever the
+/// chunk limit is reached.
+struct LdifOutput {
+    path: PathBuf,
+    compression: Compression,
+    limit: Option<ChunkLimit>,
+    writer: AsyncOutput,
+    // the current file, its number and how much has been written to it
+    current: PathBuf,
+    chunk: usize,
+    entries: u64,
+    bytes: u64,
+    summary: SinkSummary,
+}
+
+impl LdifOutput {
+    async fn create(path: PathBuf, compression: Compression, limit: Option<ChunkLimit>) -> anyhow::Result<Self> {
+        let current = match limit {
+            Some(_) => chunk_path(&path, 1),
+            None => path.clone(),
+        };
+        let writer = compression::create_async(&current, compression).await
+            .with_context(|| format!("failed to create {}", current.display()))?;
+
+        Ok(Self {
+            path,
+            compression,
+            limit,
+            writer,
+            current,
+            chunk: 1,
+            entries: 0,
+            bytes: 0,
+            summary: vec![],
+        })
+    }
 
-        if let Err(e) = writer.write(entry_string.as_bytes()).await {
-            debug!("LDIF write error: {e:#?}");
-            warn!("Failed to write entry to file: {e}");
+    async fn write_entry(&mut self, entry: &[u8]) -> anyhow::Result<()> {
+        let exceeded = match self.limit {
+            Some(ChunkLimit::Entries(max)) => self.entries >= max,
+            Some(ChunkLimit::Size(ByteSize(max))) => self.entries > 0 && self.bytes + entry.len() as u64 > max,
+            None => false,
+        };
+
+        if exceeded {
+            self.next_chunk().await?;
         }
 
+        self.writer.write_all(entry).await
+            .with_context(|| format!("failed to write entry to {}", self.current.display()))?;
+        self.entries += 1;
+        self.bytes += entry.len() as u64;
+
+        Ok(())
+    }
+
+    async fn next_chunk(&mut self) -> anyhow::Result<()> {
+        self.chunk += 1;
+        let next = chunk_path(&self.path, self.chunk);
+        let writer = compression::create_async(&next, self.compression).await
+            .with_context(|| format!("failed to create {}", next.display()))?;
+
+        let previous = std::mem::replace(&mut self.writer, writer);
+        let previous_path = std::mem::replace(&mut self.current, next);
+        close_writer(previous, previous_path, self.entries, &mut self.summary).await?;
+        self.entries = 0;
+        self.bytes = 0;
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> anyhow::Result<SinkSummary> {
+        close_writer(self.writer, self.current, self.entries, &mut self.summary).await?;
+
+        Ok(self.summary)
     }
 }
 
-fn build_entry_string(entry: LdapEntry) -> String {
+async fn close_writer(mut writer: AsyncOutput, path: PathBuf, entries: u64, summary: &mut SinkSummary) -> anyhow::Result<()> {
+    writer.shutdown().await.with_context(|| format!("failed to flush {}", path.display()))?;
+    summary.push((compression::display_name(&path), entries));
+
+    Ok(())
+}
+
+/// Returns the path of chunk number `chunk`, e.g. `out-0002.ldif.gz` for `out.ldif.gz`.
+fn chunk_path(path: &Path, chunk: usize) -> PathBuf {
+    // the number goes in front of the "real" extension, not the compression extension
+    let compression_ext = Compression::from_path(path).extension();
+    let base = match compression_ext {
+        Some(_) => path.with_extension(""),
+        None => path.to_path_buf(),
+    };
+
+    let stem = base.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut name = format!("{stem}-{chunk:04}");
+    for ext in [base.extension().and_then(|e| e.to_str()), compression_ext].into_iter().flatten() {
+        name.push('.');
+        name.push_str(ext);
+    }
+
+    path.with_file_name(name)
+}
+
+/// Builds the LDIF record for `entry`. If `change_record` is set, the entry is written as
+/// `changetype: add` change record instead of a content record.
+fn build_entry_string(entry: LdapEntry, change_record: bool) -> String {
     let (dn, attributes) = entry;
-    //              prefix                                                                      ": \n"            empty line      
-    let capacity = "dn: \n".len() + dn.len() + attributes.iter().map(|(k, v)| k.len() + v.len() + 3).sum::<usize>() + 2;
-    let mut entry_string = String::with_capacity(capacity);
+    //              prefix                                                                                          ": \n"          empty line
+    let capacity = "dn: \n".len() + dn.len() + attributes.iter().flat_map(|(k, v)| v.iter().map(|v| k.len() + v.len() + 3)).sum::<usize>() + 2;
+    let mut entry_string = String::with_capacity(capacity + CHANGETYPE_ADD.len());
     // build the entry String
-    entry_string.push_str("dn: ");
-    entry_string.push_str(dn.as_str());
+    push_line(&mut entry_string, "dn", &dn);
+
+    if change_record {
+        entry_string.push_str(CHANGETYPE_ADD);
+    }
+
+    for (key, values) in attributes.iter() {
+        for value in values.iter() {
+            push_line(&mut entry_string, key, value);
+        }
+    }
     entry_string.push('\n');
 
-    for (key, value) in attributes.iter() {
-        entry_string.push_str(key.as_str());
+    entry_string
+}
+
+/// Appends `key: value`, base64 encoding values that can't be written as-is (RFC 2849).
+fn push_line(entry_string: &mut String, key: &str, value: &str) {
+    entry_string.push_str(key);
+    if is_safe_string(value) {
         entry_string.push_str(": ");
-        // theres always exactly one value for each generated value
-        entry_string.push_str(value.iter().next().unwrap());
-        entry_string.push('\n');
+        entry_string.push_str(value);
+    } else {
+        entry_string.push_str(":: ");
+        entry_string.push_str(&BASE64.encode(value));
     }
     entry_string.push('\n');
+}
 
-    entry_string
+fn is_safe_string(value: &str) -> bool {
+    let safe_init = !value.starts_with([' ', ':', '<']) && !value.ends_with(' ');
+    safe_init && value.bytes().all(|b| b.is_ascii() && !matches!(b, b'\0' | b'\n' | b'\r'))
 }
 
 #[cfg(test)]
@@ -66,6 +266,7 @@ mod test {
     use std::collections::HashSet;
 
     use super::*;
+    use crate::testing::TempPath;
 
     #[tokio::test]
     async fn test_ldif() {
@@ -78,10 +279,78 @@ mod test {
             ]
         );
 
-        let entry_string = build_entry_string(entry);
+        let entry_string = build_entry_string(entry.clone(), false);
 
         assert_eq!(entry_string.as_str(), "dn: uid=test.user,ou=users,dc=example,dc=org\nobjectClass: inetOrgPerson\nuid: test.user\nsn: user\n\n");
 
+        let entry_string = build_entry_string(entry, true);
+
+        assert_eq!(entry_string.as_str(), "dn: uid=test.user,ou=users,dc=example,dc=org\nchangetype: add\nobjectClass: inetOrgPerson\nuid: test.user\nsn: user\n\n");
+
             
     }
+
+    #[tokio::test]
+    async fn test_ldif_export_flushed_on_close() {
+        use crate::sink::EntrySink;
+
+        let file = TempPath::new("flushed.ldif");
+        let mut sink: Box<dyn EntrySink> = Box::new(start_ldif_export_task(&file, Compression::None, None, false).await.unwrap());
+
+        for i in 0..3 {
+            let entry = (
+                format!("uid={i},dc=example,dc=org"),
+                vec![("uid".to_string(), HashSet::from([i.to_string()]))]
+            );
+            sink.send(entry).await.unwrap();
+        }
+
+        let summary = sink.close().await.unwrap();
+        let content = std::fs::read_to_string(&file).unwrap();
+
+        assert_eq!(summary, vec![(file.display().to_string(), 3)]);
+        assert_eq!(content.matches("dn: ").count(), 3);
+        assert!(content.ends_with("uid: 2\n\n"));
+    }
+
+    #[test]
+    fn test_chunk_path() {
+        assert_eq!(chunk_path(Path::new("out.ldif"), 1), PathBuf::from("out-0001.ldif"));
+        assert_eq!(chunk_path(Path::new("dir/out.ldif.gz"), 12), PathBuf::from("dir/out-0012.ldif.gz"));
+        assert_eq!(chunk_path(Path::new("out"), 2), PathBuf::from("out-0002"));
+    }
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!("1024".parse::<ByteSize>().unwrap(), ByteSize(1024));
+        assert_eq!("64k".parse::<ByteSize>().unwrap(), ByteSize(64 * 1024));
+        assert_eq!("100M".parse::<ByteSize>().unwrap(), ByteSize(100 * 1024 * 1024));
+        assert_eq!("2G".parse::<ByteSize>().unwrap(), ByteSize(2 * 1024 * 1024 * 1024));
+        assert!("0".parse::<ByteSize>().is_err());
+        assert!("twelve".parse::<ByteSize>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ldif_export_chunks() {
+        use crate::sink::EntrySink;
+
+        let dir = TempPath::new("chunks");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("out.ldif");
+        let mut sink: Box<dyn EntrySink> = Box::new(start_ldif_export_task(&file, Compression::None, Some(ChunkLimit::Entries(2)), false).await.unwrap());
+
+        for i in 0..5 {
+            let entry = (
+                format!("uid={i},dc=example,dc=org"),
+                vec![("uid".to_string(), HashSet::from([i.to_string()]))]
+            );
+            sink.send(entry).await.unwrap();
+        }
+
+        let summary = sink.close().await.unwrap();
+        let last_chunk = std::fs::read_to_string(dir.join("out-0003.ldif")).unwrap();
+
+        assert_eq!(summary.iter().map(|(_, c)| *c).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert_eq!(last_chunk, "dn: uid=4,dc=example,dc=org\nuid: 4\n\n");
+    }
 }
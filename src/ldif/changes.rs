@@ -0,0 +1,260 @@
+//! Companion files containing LDIF change records for the exported entries. They allow
+//! replaying churn workloads with standard tooling, e.g. `ldapmodify -f modify.ldif`.
+//!
+//! * The modify export writes `changetype: modify` records for a random subset of the entries,
+//!   replacing one attribute with a freshly generated value.
+//! * The delete export writes `changetype: delete` records for all entries, children first, so
+//!   the generated tree can be removed again.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use rand::Rng;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+
+use super::{push_line, LdifOutput};
+use crate::compression::{self, Compression};
+use crate::entries::EntryGenerator;
+use crate::random::{seeded, with_rng};
+use crate::sink::{ChannelSink, SinkSummary};
+use crate::types::{LdapEntry, LdifReceiver};
+
+/// Mixed into the seed of the run, so the random values of the modify records don't repeat the
+/// ones of the generated entries.
+const MODIFY_SEED: u64 = 0x6d6f_6469_6679;
+
+/// Starts the modify export. Each entry is modified with a probability of `ratio`, entries
+/// without a matching generator in `generators` are skipped. The same `seed` always modifies
+/// the same entries in the same way.
+pub async fn start_modify_export_task<P: AsRef<Path>>(
+    export_file: P,
+    compression: Compression,
+    ratio: f64,
+    generators: &'static HashMap<String, EntryGenerator>,
+    seed: u64,
+) -> anyhow::Result<ChannelSink> {
+    if !(0.0..=1.0).contains(&ratio) {
+        bail!("modify ratio must be between 0 and 1, got {ratio}");
+    }
+
+    let (tx, rx) = unbounded_channel();
+    let name = format!("LDIF modify export to {}", compression::display_name(export_file.as_ref()));
+    let output = LdifOutput::create(export_file.as_ref().to_path_buf(), compression, None).await?;
+
+    let task = tokio::spawn(async move { modify_exporter(rx, output, ratio, generators, seed).await });
+
+    Ok(ChannelSink::new(name, tx, task))
+}
+
+async fn modify_exporter(
+    rx: LdifReceiver,
+    mut output: LdifOutput,
+    ratio: f64,
+    generators: &'static HashMap<String, EntryGenerator>,
+    seed: u64,
+) -> anyhow::Result<SinkSummary> {
+    let mut stream = UnboundedReceiverStream::new(rx);
+    let mut index = 0;
+    while let Some(entry) = stream.next().await {
+        let record = seeded(seed ^ MODIFY_SEED, index, || {
+            if !with_rng(|rng| rng.gen_bool(ratio)) {
+                return None;
+            }
+
+            let (attribute, value) = find_generator(&entry, generators)?.generate_modification()?;
+            Some(build_modify_string(&entry.0, &attribute, &value))
+        });
+        index += 1;
+
+        if let Some(record) = record {
+            output.write_entry(record.as_bytes()).await?;
+        }
+    }
+
+    output.finish().await
+}
+
+/// Starts the delete export. As entries have to be deleted leaves first, the DNs are kept in
+/// memory and only written once the sink is closed.
+pub async fn start_delete_export_task<P: AsRef<Path>>(
+    export_file: P,
+    compression: Compression,
+) -> anyhow::Result<ChannelSink> {
+    let (tx, rx) = unbounded_channel();
+    let name = format!("LDIF delete export to {}", compression::display_name(export_file.as_ref()));
+    let output = LdifOutput::create(export_file.as_ref().to_path_buf(), compression, None).await?;
+
+    let task = tokio::spawn(async move { delete_exporter(rx, output).await });
+
+    Ok(ChannelSink::new(name, tx, task))
+}
+
+async fn delete_exporter(rx: LdifReceiver, mut output: LdifOutput) -> anyhow::Result<SinkSummary> {
+    let mut stream = UnboundedReceiverStream::new(rx);
+    let mut dns = vec![];
+    while let Some((dn, _)) = stream.next().await {
+        dns.push(dn);
+    }
+
+    // parents are always received before their children, so the reverse order deletes
+    // the children first
+    for dn in dns.iter().rev() {
+        output.write_entry(build_delete_string(dn).as_bytes()).await?;
+    }
+
+    output.finish().await
+}
+
+/// Returns the generator for one of the object classes of `entry`.
+fn find_generator<'g>(
+    entry: &LdapEntry,
+    generators: &'g HashMap<String, EntryGenerator>,
+) -> Option<&'g EntryGenerator> {
+    entry
+        .1
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("objectclass"))
+        .flat_map(|(_, classes)| classes.iter())
+        .find_map(|class| generators.get(class))
+}
+
+fn build_modify_string(dn: &str, attribute: &str, value: &str) -> String {
+    let mut record = String::new();
+    push_line(&mut record, "dn", dn);
+    record.push_str("changetype: modify\n");
+    push_line(&mut record, "replace", attribute);
+    push_line(&mut record, attribute, value);
+    record.push_str("-\n\n");
+
+    record
+}
+
+fn build_delete_string(dn: &str) -> String {
+    let mut record = String::new();
+    push_line(&mut record, "dn", dn);
+    record.push_str("changetype: delete\n\n");
+
+    record
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::modifiers::parser::Token;
+    use crate::sink::EntrySink;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn test_change_records() {
+        assert_eq!(
+            build_modify_string("uid=test,dc=example,dc=org", "sn", "user"),
+            "dn: uid=test,dc=example,dc=org\nchangetype: modify\nreplace: sn\nsn: user\n-\n\n"
+        );
+        assert_eq!(
+            build_delete_string("uid=test,dc=example,dc=org"),
+            "dn: uid=test,dc=example,dc=org\nchangetype: delete\n\n"
+        );
+    }
+
+    #[test]
+    fn test_unsafe_change_records() {
+        // non-ASCII DNs and values with a leading space, `:` or `<` are base64 encoded
+        assert_eq!(
+            build_modify_string("uid=müller,dc=example,dc=org", "description", " leading space"),
+            "dn:: dWlkPW3DvGxsZXIsZGM9ZXhhbXBsZSxkYz1vcmc=\nchangetype: modify\nreplace: description\ndescription:: IGxlYWRpbmcgc3BhY2U=\n-\n\n"
+        );
+        assert_eq!(
+            build_modify_string("uid=test,dc=example,dc=org", "sn", ":colon"),
+            "dn: uid=test,dc=example,dc=org\nchangetype: modify\nreplace: sn\nsn:: OmNvbG9u\n-\n\n"
+        );
+        assert_eq!(
+            build_delete_string("cn=Ærø,dc=example,dc=org"),
+            "dn:: Y249w4Zyw7gsZGM9ZXhhbXBsZSxkYz1vcmc=\nchangetype: delete\n\n"
+        );
+    }
+
+    #[test]
+    fn test_find_generator() {
+        let generators = HashMap::from([(
+            "inetOrgPerson".to_string(),
+            EntryGenerator::new(
+                "inetOrgPerson".to_string(),
+                "uid".to_string(),
+                HashMap::from([
+                    ("uid".to_string(), Token::String("test".to_string())),
+                    ("sn".to_string(), Token::String("user".to_string())),
+                ]),
+            ),
+        )]);
+        let entry = (
+            "uid=test,dc=example,dc=org".to_string(),
+            vec![(
+                "objectClass".to_string(),
+                HashSet::from(["top".to_string(), "inetOrgPerson".to_string()]),
+            )],
+        );
+
+        let generator = find_generator(&entry, &generators).expect("generator for inetOrgPerson");
+
+        // the rdn attribute is never modified
+        assert_eq!(
+            generator.generate_modification(),
+            Some(("sn".to_string(), "user".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_modify_export_seeded() {
+        let generators: &'static HashMap<String, EntryGenerator> = Box::leak(Box::new(HashMap::from([(
+            "inetOrgPerson".to_string(),
+            EntryGenerator::new(
+                "inetOrgPerson".to_string(),
+                "uid".to_string(),
+                HashMap::from([
+                    ("uid".to_string(), Token::String("test".to_string())),
+                    ("sn".to_string(), Token::String("user".to_string())),
+                ]),
+            ),
+        )])));
+
+        let export = |seed: u64| async move {
+            let file = TempPath::new(&format!("modify-{seed}.ldif"));
+            let mut sink: Box<dyn EntrySink> =
+                Box::new(start_modify_export_task(&file, Compression::None, 0.5, generators, seed).await.unwrap());
+            for i in 0..50 {
+                let classes = HashSet::from(["inetOrgPerson".to_string()]);
+                sink.send((format!("uid=user{i},dc=example,dc=org"), vec![("objectClass".to_string(), classes)])).await.unwrap();
+            }
+            sink.close().await.unwrap();
+
+            std::fs::read_to_string(&file).unwrap()
+        };
+
+        let content = export(42).await;
+        assert!(content.contains("changetype: modify"));
+        assert_eq!(content, export(42).await);
+        assert_ne!(content, export(43).await);
+    }
+
+    #[tokio::test]
+    async fn test_delete_export_children_first() {
+        let file = TempPath::new("delete.ldif");
+        let mut sink: Box<dyn EntrySink> = Box::new(start_delete_export_task(&file, Compression::None).await.unwrap());
+
+        for dn in ["ou=users,dc=example,dc=org", "uid=test,ou=users,dc=example,dc=org"] {
+            sink.send((dn.to_string(), vec![])).await.unwrap();
+        }
+
+        sink.close().await.unwrap();
+        let content = std::fs::read_to_string(&file).unwrap();
+
+        assert_eq!(
+            content,
+            "dn: uid=test,ou=users,dc=example,dc=org\nchangetype: delete\n\ndn: ou=users,dc=example,dc=org\nchangetype: delete\n\n"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Reads entries from existing LDIF files, e.g. to insert a previously exported tree.
+//!
+//! The parser supports the parts of RFC 2849 that are used by common exports: folded lines,
+//! comments, base64 encoded values (`attr:: dmFsdWU=`), multi-valued attributes and
+//! `changetype: add` records. Other change records as well as values referenced by URL
+//! (`attr:< file:///...`) are rejected.
+
+use std::collections::HashSet;
+
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use crate::reader::EntryParser;
+use crate::types::LdapEntry;
+
+/// Line based LDIF parser. Lines are fed one by one, a complete entry is returned once the
+/// blank line terminating it has been read. As continuation lines follow the line they
+/// belong to, every logical line is only processed once the next physical line is known.
+#[derive(Debug, Default)]
+pub struct LdifParser {
+    line_number: usize,
+    /// The logical line being assembled and the line number it started on.
+    current: Option<(String, usize)>,
+    /// Whether the current logical line is a comment, whose continuations are skipped.
+    in_comment: bool,
+    record: Option<LdapEntry>,
+}
+
+impl EntryParser for LdifParser {
+    const FORMAT: &'static str = "LDIF";
+
+    /// Processes the next physical line.
+    fn feed_line(&mut self, line: &str) -> anyhow::Result<Option<LdapEntry>> {
+        self.line_number += 1;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        if let Some(continuation) = line.strip_prefix(' ') {
+            match self.current {
+                Some((ref mut current, _)) => current.push_str(continuation),
+                None if self.in_comment => (),
+                None => bail!("line {}: continuation line without a preceding line", self.line_number),
+            }
+            return Ok(None);
+        }
+
+        self.process_current()?;
+        self.in_comment = false;
+
+        if line.is_empty() {
+            return Ok(self.record.take());
+        }
+
+        if line.starts_with('#') {
+            self.in_comment = true;
+        } else {
+            self.current = Some((line.to_string(), self.line_number));
+        }
+
+        Ok(None)
+    }
+
+    /// Processes the remaining input at the end of the file and returns the last entry if it
+    /// was not terminated by a blank line.
+    fn finish(mut self) -> anyhow::Result<Option<LdapEntry>> {
+        self.process_current()?;
+        Ok(self.record.take())
+    }
+}
+
+impl LdifParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn process_current(&mut self) -> anyhow::Result<()> {
+        let Some((line, line_number)) = self.current.take() else {
+            return Ok(());
+        };
+
+        let (key, value) = parse_line(&line).with_context(|| format!("line {line_number}"))?;
+
+        let Some((_, ref mut attributes)) = self.record else {
+            if key.eq_ignore_ascii_case("version") {
+                return Ok(());
+            }
+
+            if !key.eq_ignore_ascii_case("dn") {
+                bail!("line {line_number}: expected `dn`, found `{key}`");
+            }
+
+            self.record = Some((value, vec![]));
+            return Ok(());
+        };
+
+        if key.eq_ignore_ascii_case("changetype") {
+            if !value.eq_ignore_ascii_case("add") {
+                bail!("line {line_number}: unsupported changetype `{value}`, only entries can be inserted");
+            }
+            return Ok(());
+        }
+
+        match attributes.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some((_, values)) => {
+                values.insert(value);
+            }
+            None => attributes.push((key.to_string(), HashSet::from([value]))),
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits a logical line into attribute description and (decoded) value.
+fn parse_line(line: &str) -> anyhow::Result<(&str, String)> {
+    let Some((key, value)) = line.split_once(':') else {
+        bail!("expected `attribute: value`, found `{line}`");
+    };
+
+    if key.is_empty() {
+        bail!("missing attribute name");
+    }
+
+    let value = if let Some(encoded) = value.strip_prefix(':') {
+        let decoded = BASE64
+            .decode(encoded.trim())
+            .with_context(|| format!("invalid base64 value for `{key}`"))?;
+        String::from_utf8(decoded).map_err(|_| anyhow!("binary value for `{key}` is not supported"))?
+    } else if value.starts_with('<') {
+        bail!("URL value for `{key}` is not supported");
+    } else {
+        value.trim_start_matches(' ').to_string()
+    };
+
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(input: &str) -> anyhow::Result<Vec<LdapEntry>> {
+        let mut parser = LdifParser::new();
+        let mut entries = vec![];
+        for line in input.lines() {
+            entries.extend(parser.feed_line(line)?);
+        }
+        entries.extend(parser.finish()?);
+
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let input = "version: 1\n\
+                     # a comment\n \
+                      that is folded\n\
+                     dn: ou=users,dc=example,dc=org\n\
+                     objectClass: top\n\
+                     objectClass: organizationalUnit\n\
+                     ou: users\n\
+                     \n\
+                     dn: uid=test,ou=us\n \
+                     ers,dc=example,dc=org\r\n\
+                     changetype: add\n\
+                     uid: test\n\
+                     cn:: SsO8cmdlbg==\n\
+                     description: first\n\
+                     DESCRIPTION: second";
+
+        let entries = parse(input).unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    "ou=users,dc=example,dc=org".to_string(),
+                    vec![
+                        (
+                            "objectClass".to_string(),
+                            HashSet::from(["top".to_string(), "organizationalUnit".to_string()])
+                        ),
+                        ("ou".to_string(), HashSet::from(["users".to_string()])),
+                    ]
+                ),
+                (
+                    "uid=test,ou=users,dc=example,dc=org".to_string(),
+                    vec![
+                        ("uid".to_string(), HashSet::from(["test".to_string()])),
+                        ("cn".to_string(), HashSet::from(["Jürgen".to_string()])),
+                        (
+                            "description".to_string(),
+                            HashSet::from(["first".to_string(), "second".to_string()])
+                        ),
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_exported_entry() {
+        let entry = (
+            "cn=Jürgen,dc=example,dc=org".to_string(),
+            vec![
+                ("cn".to_string(), HashSet::from(["Jürgen".to_string()])),
+                ("mail".to_string(), HashSet::from(["a@example.org".to_string(), "b@example.org".to_string()])),
+                ("description".to_string(), HashSet::from([" leading space".to_string()])),
+            ],
+        );
+
+        let exported = crate::ldif::build_entry_string(entry.clone(), true);
+
+        assert_eq!(parse(&exported).unwrap(), vec![entry]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse("dn: uid=test,dc=example,dc=org\nchangetype: delete\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+
+        assert!(parse("uid: test\n").is_err());
+        assert!(parse("dn: uid=test,dc=example,dc=org\njpegPhoto:< file:///tmp/photo.jpg\n").is_err());
+        assert!(parse("dn: uid=test,dc=example,dc=org\ncn:: not base64!\n").is_err());
+        assert!(parse(" continuation\n").is_err());
+    }
+}
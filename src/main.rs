@@ -8,63 +8,106 @@ extern crate log;
 extern crate anyhow;
 
 use std::collections::HashMap;
-use tokio::sync::{mpsc, oneshot};
 
 use anyhow::bail;
 use clap::Parser;
 
+mod base;
+mod checkpoint;
 mod cli;
 mod cmd;
+mod compression;
 mod config;
+mod controls;
+mod credentials;
 mod csv;
+mod delete;
+mod dn;
 mod entries;
-mod error;
 mod format;
+mod insert;
+mod jsonl;
 mod ldap_pool;
 mod modifiers;
+mod password;
+mod result_codes;
+mod retry;
 mod types;
+mod verify;
 mod progress;
+mod rate;
+mod reader;
+mod random;
 mod ldif;
+mod sink;
+#[cfg(test)]
+mod testing;
 
 use cli::CliArgs;
 use cli::MainCommand;
-use config::{Config, LdapConfig};
+use config::Config;
+use csv::CsvFormat;
 use entries::EntryGenerator;
 use format::Format;
-use ldap_pool::LdapPool;
 use modifiers::file_cache::{set_file_cache, FileCache};
-use crate::{csv::{CsvSender, start_csv_task}, progress::ProgressMessage};
 
-static mut GENERATORS: Option<HashMap<String, EntryGenerator>> = None;
-static mut HIERARCHY: Option<Vec<(String, u64)>> = None;
 lazy_static! {
     static ref ARGS: CliArgs = CliArgs::parse();
 }
 
-pub type ResultReceiver = oneshot::Receiver<anyhow::Result<String>>;
-pub type ResultSender = oneshot::Sender<anyhow::Result<String>>;
-pub type Receiver = mpsc::UnboundedReceiver<(String, &'static EntryGenerator, ResultSender)>;
-pub type Sender = mpsc::UnboundedSender<(String, &'static EntryGenerator, ResultSender)>;
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // TODO: Add config option for this
     let args = &ARGS;
     let cfg = args.config_file.as_str();
 
-    let config = Config::load_from_file(cfg)?;
+    let mut config = Config::load_from_file(cfg)?;
+    // log to stderr, stdout might be used to export entries
+    env_logger::Builder::new()
+        .target(env_logger::Target::Stderr)
+        .filter_level(config.log())
+        .parse_default_env()
+        .init();
+
+    if let Some(ref profile) = args.profile {
+        config.apply_profile(profile)?;
+        info!("Using profile {profile}");
+    }
 
-    let Some(format_file_path) = (match config.defaults() {
+    let format_file_path = match config.defaults() {
         Some(defaults) => args.format_file.as_deref().or(defaults.format_file()),
         None => args.format_file.as_deref(),
-    }) else {
-        bail!("path to format file must be specified either in the configuration or using the --format-file option");
     };
 
+    let (hierarchy_weights, generators, csv_format) = match format_file_path {
+        Some(format_file_path) => load_format(format_file_path).await?,
+        // entries read from a file or deleted don't need to be generated
+        None if !args.generates_entries() => (vec![], HashMap::new(), CsvFormat::default()),
+        None => bail!("path to format file must be specified either in the configuration or using the --format-file option"),
+    };
+
+    cmd::set_hierarchy(hierarchy_weights);
+    cmd::set_generators(generators);
+    cmd::set_csv_format(csv_format);
+
+    let res = match args.cmd {
+        MainCommand::Export { .. } => cmd::export_cmd(args, &config).await,
+        MainCommand::Insert { .. } => cmd::insert_cmd(args, &config).await,
+        MainCommand::Delete { .. } => cmd::delete_cmd(args, &config).await,
+        MainCommand::Verify { .. } => cmd::verify_cmd(args, &config).await,
+    };
+
+    res
+}
+
+/// Loads the format file and builds the hierarchy, the entry generators and the csv layout
+/// from it.
+async fn load_format(format_file_path: &str) -> anyhow::Result<(Vec<(String, u64)>, HashMap<String, EntryGenerator>, CsvFormat)> {
     info!("Trying to load format file at {format_file_path}");
     let format = Format::load_from_file(format_file_path)?;
     let hierarchy_weights = format.hierarchy_tuples();
-    let generators = match format.to_entry_generators() {
+    let csv_format = format.csv().clone();
+    let generators = match format.into_entry_generators() {
         Ok(g) => g,
         Err(e) => {
             error!("Failed to build entry generators from format file: {e}");
@@ -77,23 +120,7 @@ async fn main() -> anyhow::Result<()> {
         return Err(e);
     }
 
-    // you're not supposed to fight or work around the borrow checker,
-    // but I need to hand in a bachelors thesis in less than 2 months, so
-    // I don't have the time to work around this properly.
-    unsafe {
-        GENERATORS = Some(generators.clone());
-        HIERARCHY = Some(hierarchy_weights.clone());
-    }
-    
-    cmd::set_hierarchy(hierarchy_weights);
-    cmd::set_generators(generators);
-
-    let res = match args.cmd {
-        MainCommand::Export { .. } => cmd::export_cmd(&args).await,
-        MainCommand::Insert { .. } => cmd::insert_cmd(&args).await
-    };
-
-    res
+    Ok((hierarchy_weights, generators, csv_format))
 }
 
 async fn build_file_cache<'e, T>(generators: T) -> anyhow::Result<()>
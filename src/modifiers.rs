@@ -1,23 +1,14 @@
 pub(crate) mod file_cache;
 pub(crate) mod parser;
-mod types;
 
 use file_cache::FileCache;
 use parser::Modifier as ModifierKind;
 use std::io;
-use std::{path::PathBuf, str::FromStr};
-
-pub use types::*;
+use std::path::PathBuf;
 
 use self::file_cache::get_file_cache;
 use self::parser::Token;
 
-/// Base trait of a modifier. The modifier gets passed all string arguments or the
-/// output of nested modifiers, in the order they are specified in the configuration.
-pub trait Modifier {
-    fn apply(args: Vec<String>) -> String;
-}
-
 pub type ModifierTree = Token;
 
 impl ModifierTree {
@@ -71,7 +62,7 @@ impl ModifierTree {
         res
     }
 
-    fn apply_modifier(&self, modifier: ModifierKind, args: &Vec<ModifierTree>) -> String {
+    fn apply_modifier(&self, modifier: ModifierKind, args: &[ModifierTree]) -> String {
         match modifier {
             ModifierKind::Uppercase => args
                 .iter()
@@ -7,11 +7,14 @@
 
 use std::{collections::HashMap, path::PathBuf};
 use std::io;
+use std::sync::OnceLock;
 use tokio::fs;
 use tokio::io::{self as tio, AsyncBufReadExt};
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
-static mut FILE_CACHE: Option<FileCache> = None;
+use crate::random::with_rng;
+
+static FILE_CACHE: OnceLock<FileCache> = OnceLock::new();
 
 /// A FileCache holds a given number of text files in memory, stored on a line-by-line basis.
 /// File modifiers may request data from a given file, in which case a random line will be 
@@ -55,7 +58,7 @@ impl FileCache {
     /// # Panics
     /// Will panic if the file is not present in the cache.
     pub fn get_string(&self, file: &PathBuf) -> &'_ str {
-        let index = thread_rng().gen_range(0..self.cache[file].len());
+        let index = with_rng(|rng| rng.gen_range(0..self.cache[file].len()));
 
         self.cache[file][index].as_str()
     }
@@ -66,11 +69,8 @@ impl FileCache {
 /// # Panics
 /// Panics if you try to swap the file cache.
 pub fn set_file_cache(cache: FileCache) {
-    unsafe {
-        match FILE_CACHE {
-            Some(_) => panic!("CANNOT SET FILE_CACHE TWICE"),
-            None => FILE_CACHE = Some(cache)
-        }
+    if FILE_CACHE.set(cache).is_err() {
+        panic!("CANNOT SET FILE_CACHE TWICE")
     }
 }
 
@@ -79,11 +79,9 @@ pub fn set_file_cache(cache: FileCache) {
 /// # Panics
 /// Panics if the file cache has not been set
 pub fn get_file_cache() -> &'static FileCache {
-    unsafe {
-        match FILE_CACHE {
-            Some(ref c) => c,
-            None => panic!("get_file_cache() MUST be called AFTER set_file_cache")
-        }
+    match FILE_CACHE.get() {
+        Some(c) => c,
+        None => panic!("get_file_cache() MUST be called AFTER set_file_cache")
     }
 }
 
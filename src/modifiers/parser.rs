@@ -35,11 +35,10 @@
 
 use std::str::FromStr;
 
-use pest::{error::Error, iterators::Pair, iterators::Pairs, Parser};
+use pest::{iterators::Pair, Parser};
 use thiserror::Error;
 
-pub type ParserResult<'e> = Result<Token, ParserError>;
-pub type PestResult<'r> = Result<Pairs<'r, Rule>, Error<Rule>>;
+pub type ParserResult = Result<Token, ParserError>;
 
 /// Individual entities that can be encountered when parsing config files
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -59,16 +58,10 @@ pub enum Modifier {
 
 #[derive(Debug, Error)]
 pub enum ParserError {
-    #[error("unclosed string beginning at {0}")]
-    UnclosedString(usize),
     #[error("Encountered unknown modifier name: {0}")]
     UnknownModifier(String),
-    #[error("Value cannot be empty")]
-    EmptyValue,
-    #[error("unmatches parenthesis")]
-    UnmatchesParenthesis,
-    #[error("illegal character {0} at {1}")]
-    IllegalCharacter(char, usize),
+    #[error("invalid modifier syntax: {0}")]
+    Syntax(#[from] Box<pest::error::Error<Rule>>),
 }
 
 #[derive(Debug, Parser)]
@@ -76,7 +69,7 @@ pub enum ParserError {
 pub struct CfgParser;
 
 pub fn parse(input: &str) -> ParserResult {
-    let mut res = CfgParser::parse(Rule::line, input).expect("Valid input");
+    let mut res = CfgParser::parse(Rule::line, input).map_err(Box::new)?;
 
     let res = res.next().expect("at least one pair");
 
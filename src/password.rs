@@ -0,0 +1,109 @@
+//! Sources of the bind password.
+//!
+//! The password is taken from the first source that is set, in this order:
+//!
+//! 1. the command line: `--password` (prompt), `--password-file` or `--password-env`
+//! 2. the `LDAPFILL_PASSWORD` environment variable
+//! 3. the `[ldap]` section: `password`, `password-file` or `password-env`
+//! 4. an interactive prompt, if a simple bind is performed and stdin is a terminal
+
+use std::io::IsTerminal;
+use std::path::Path;
+
+use anyhow::Context;
+
+/// The environment variable that is checked if no password is given on the command line.
+pub const PASSWORD_ENV: &str = "LDAPFILL_PASSWORD";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordSource {
+    Prompt,
+    File(String),
+    Env(String),
+    Value(String),
+}
+
+impl PasswordSource {
+    /// Reads the password. `server` is only used for the prompt.
+    pub fn read(&self, server: &str) -> anyhow::Result<String> {
+        match self {
+            PasswordSource::Prompt => {
+                rpassword::prompt_password(format!("Password for {server}: ")).context("failed to read password")
+            }
+            PasswordSource::File(file) => read_password_file(Path::new(file)),
+            PasswordSource::Env(var) => {
+                std::env::var(var).with_context(|| format!("environment variable {var} is not set"))
+            }
+            PasswordSource::Value(password) => Ok(password.to_owned()),
+        }
+    }
+
+    /// Returns the prompt if it can be shown, i.e. stdin is a terminal.
+    pub fn interactive_prompt() -> Option<Self> {
+        std::io::stdin().is_terminal().then_some(PasswordSource::Prompt)
+    }
+}
+
+/// Reads the first line of `file`. On unix, files that can be accessed by the group or others
+/// are rejected.
+fn read_password_file(file: &Path) -> anyhow::Result<String> {
+    check_permissions(file)?;
+
+    let content = std::fs::read_to_string(file)
+        .with_context(|| format!("failed to read password file {}", file.display()))?;
+
+    // the trailing newline is not part of the password
+    Ok(content.lines().next().unwrap_or_default().to_owned())
+}
+
+#[cfg(unix)]
+fn check_permissions(file: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::metadata(file)
+        .with_context(|| format!("failed to read password file {}", file.display()))?;
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "password file {} is accessible by other users (mode {:o}), restrict it using `chmod 600`",
+            file.display(),
+            mode & 0o777
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_file: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_password_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = TempPath::new("password");
+        std::fs::write(&file, "secret\n").unwrap();
+        let source = PasswordSource::File(file.display().to_string());
+
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let err = source.read("ldap://localhost").unwrap_err();
+        assert!(err.to_string().contains("chmod 600"), "{err}");
+
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(source.read("ldap://localhost").unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_password_env() {
+        assert!(PasswordSource::Env("LDAPFILL_TEST_UNSET_PASSWORD".to_string()).read("").is_err());
+        assert_eq!(PasswordSource::Value("secret".to_string()).read("").unwrap(), "secret");
+    }
+}
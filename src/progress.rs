@@ -1,70 +1,84 @@
-/// Provides a progress bar that shows how many entries have been created. 
+//! Provides a progress bar that shows how many entries have been created.
 
 use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, unbounded_channel};
+use tokio::task::JoinHandle;
 use tokio::time;
 use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle, HumanDuration};
+use indicatif::{ProgressBar, ProgressStyle};
 
-// TODO: Transport messages via the channel. This allows for smoother display and 
-// avoid weird left-overs when the bar moves down a line bcause we printed 
+// Messages are transported via the channel. This allows for smoother display and
+// avoids weird left-overs when the bar moves down a line because we printed
 // a log statement.
 #[derive(Debug, Clone)]
 pub enum ProgressMessage {
     Progress,
     ProgressWithMessage(String),
-    Message(String)
+    /// Entries that have been processed by a previous run.
+    Resume(u64),
+    /// Shown next to the current rate, e.g. the rate limit.
+    Status(String),
 }
 
-pub type ProgressData = ProgressMessage;
-pub type ProgressSender = UnboundedSender<ProgressData>;
-pub type ProgressReceiver = UnboundedReceiver<ProgressData>;
+pub type ProgressSender = UnboundedSender<ProgressMessage>;
+pub type ProgressReceiver = UnboundedReceiver<ProgressMessage>;
 
-pub async fn start_progress_task(max_count: u64) -> ProgressSender {
+/// Starts the progress bar task. The bar is finished once all senders have been dropped,
+/// await the returned handle to make sure the final message has been printed. If the number
+/// of entries is not known in advance, a spinner is shown instead.
+pub fn start_progress_task(max_count: Option<u64>) -> (ProgressSender, JoinHandle<()>) {
     let (tx, rx) = unbounded_channel();
-    tokio::spawn(async move { progress_task(max_count, rx).await });
+    let handle = tokio::spawn(async move { progress_task(max_count, rx).await });
 
-    tx
+    (tx, handle)
 }
 
-async fn progress_task(max_count: u64, rx: ProgressReceiver) {
-    let style = ProgressStyle::with_template("{wide_bar} [{pos}/{len}] ({percent}%) {msg} [{eta}]").expect("valid style");
-    let bar = ProgressBar::new(max_count);
-    bar.set_style(style);
+async fn progress_task(max_count: Option<u64>, rx: ProgressReceiver) {
+    let bar = match max_count {
+        Some(max_count) => {
+            let style = ProgressStyle::with_template("{wide_bar} [{pos}/{len}] ({percent}%) {msg} [{elapsed}/{eta}]").expect("valid style");
+            ProgressBar::new(max_count).with_style(style)
+        }
+        None => {
+            let style = ProgressStyle::with_template("{spinner} [{pos}] {msg} [{elapsed}]").expect("valid style");
+            ProgressBar::new_spinner().with_style(style)
+        }
+    };
     let mut stream = UnboundedReceiverStream::new(rx);
     let mut count = 0;
     let start = time::Instant::now();
     let mut current_interval = start;
     let mut current_count = 0;
+    let mut status = String::new();
 
     while let Some(data) = stream.next().await {
-        let inc = match data {
-            ProgressMessage::Progress => 1,
-            ProgressMessage::ProgressWithMessage(s) => {
-                bar.println(s);
-                1
-            },
-            ProgressMessage::Message(s) => {
-                bar.println(s);
-                0
+        match data {
+            ProgressMessage::Progress => (),
+            ProgressMessage::ProgressWithMessage(s) => bar.println(s),
+            ProgressMessage::Resume(position) => {
+                // not counted for the rate
+                bar.set_position(position);
+                continue;
+            }
+            ProgressMessage::Status(s) => {
+                status = format!(" ({s})");
+                continue;
             }
-        };
-        bar.inc(inc);
-        count += inc;
-        current_count += inc;
+        }
+        bar.inc(1);
+        count += 1;
+        current_count += 1;
 
         let now = time::Instant::now();
         if (now - current_interval).as_secs() >= 1 {
-            let msg = format!("{current_count} entries/second");
-            bar.set_message(msg.clone());
+            let msg = format!("{current_count} entries/second{status}");
+            bar.set_message(msg);
             current_count = 0;
             current_interval = now;
         }
     }
 
-    let end = time::Instant::now();
-    let total_duration = end-start;
-    let avg = count/total_duration.as_secs();
-    let msg = format!("Created {avg} entries/second on average");
+    let total_duration = time::Instant::now() - start;
+    let avg = (count as f64 / total_duration.as_secs_f64().max(f64::EPSILON)) as u64;
+    let msg = format!("Processed {avg} entries/second on average");
     bar.finish_with_message(msg);
-
 }
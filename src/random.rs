@@ -0,0 +1,56 @@
+//! The random number generator used to generate entries.
+//!
+//! Every entry is generated using its own generator, seeded from the seed of the run and the
+//! position of the entry. Generating the same hierarchy with the same seed therefore produces
+//! the same entries, which allows resuming an interrupted insert.
+
+use std::cell::RefCell;
+
+use rand::rngs::StdRng;
+use rand::{thread_rng, RngCore, SeedableRng};
+
+thread_local! {
+    static ENTRY_RNG: RefCell<Option<StdRng>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, using the generator of entry number `index` for all random values requested by
+/// [`with_rng`] in the meantime.
+pub fn seeded<T>(seed: u64, index: u64, f: impl FnOnce() -> T) -> T {
+    // spread the bits of the index, so neighbouring entries don't get similar seeds
+    let rng = StdRng::seed_from_u64(seed ^ index.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    let previous = ENTRY_RNG.with(|cell| cell.replace(Some(rng)));
+    let result = f();
+    ENTRY_RNG.with(|cell| cell.replace(previous));
+
+    result
+}
+
+/// Calls `f` with the generator of the current entry or, outside of [`seeded`], with the
+/// generator of the thread.
+pub fn with_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    ENTRY_RNG.with(|cell| match cell.borrow_mut().as_mut() {
+        Some(rng) => f(rng),
+        None => f(&mut thread_rng()),
+    })
+}
+
+/// A new random seed for runs without a given seed.
+pub fn random_seed() -> u64 {
+    thread_rng().next_u64()
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn test_seeded() {
+        let values = |seed, index| seeded(seed, index, || with_rng(|rng| (rng.gen::<u64>(), rng.gen::<u64>())));
+
+        assert_eq!(values(42, 7), values(42, 7));
+        assert_ne!(values(42, 7), values(42, 8));
+        assert_ne!(values(42, 7), values(43, 7));
+    }
+}
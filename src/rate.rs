@@ -0,0 +1,139 @@
+//! Limits the rate of operations sent to the server, e.g. to avoid saturating replication.
+//!
+//! Each limit is enforced by a token bucket holding at most a tenth of a second worth of
+//! operations. Callers take a token before every operation and wait if the bucket is empty.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// The `[ldap.rate-limit]` section of the configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RateLimit {
+    /// The maximum number of operations per second over all connections.
+    pub ops_per_second: Option<f64>,
+    /// The maximum number of operations per second of every single connection.
+    pub per_connection: Option<f64>,
+    /// The number of seconds over which the limits are raised from 1 operation per second to
+    /// their configured value.
+    pub ramp_up: u64,
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for rate in [self.ops_per_second, self.per_connection].into_iter().flatten() {
+            if !(rate.is_finite() && rate > 0.0) {
+                bail!("rate limits must be positive, not {rate}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A token bucket refilled at a fixed rate, optionally ramping up to it.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    ramp_up: Duration,
+    start: Instant,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative while callers are waiting for tokens.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, ramp_up: Duration) -> Self {
+        let start = Instant::now();
+        Self { rate, ramp_up, start, bucket: Mutex::new(Bucket { tokens: 1.0, last_refill: start }) }
+    }
+
+    /// The current rate in operations per second.
+    pub fn rate(&self) -> f64 {
+        self.rate_at(Instant::now())
+    }
+
+    fn rate_at(&self, now: Instant) -> f64 {
+        if self.ramp_up.is_zero() {
+            return self.rate;
+        }
+
+        let progress = (now - self.start).as_secs_f64() / self.ramp_up.as_secs_f64();
+        (self.rate * progress).clamp(1.0_f64.min(self.rate), self.rate)
+    }
+
+    /// Takes a token, waiting until one is available.
+    pub async fn acquire(&self) {
+        let delay = self.reserve(Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Takes a token and returns how long to wait until it is available.
+    fn reserve(&self, now: Instant) -> Duration {
+        let rate = self.rate_at(now);
+        let capacity = (rate / 10.0).max(1.0);
+        let mut bucket = self.bucket.lock().expect("rate limiter mutex poisoned");
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.last_refill = now;
+        bucket.tokens -= 1.0;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let limiter = RateLimiter::new(10.0, Duration::ZERO);
+        let now = limiter.start;
+
+        // the first token is available immediately, the others have to be waited for
+        assert_eq!(limiter.reserve(now), Duration::ZERO);
+        let delays: Vec<u128> = (0..3).map(|_| limiter.reserve(now).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 300]);
+
+        // the waiting callers used up the tokens of the next 300ms
+        assert_eq!(limiter.reserve(now + Duration::from_millis(400)), Duration::ZERO);
+        // at most one token is saved up at this rate
+        assert_eq!(limiter.reserve(now + Duration::from_secs(10)), Duration::ZERO);
+        assert_eq!(limiter.reserve(now + Duration::from_secs(10)).as_millis(), 100);
+    }
+
+    #[test]
+    fn test_ramp_up() {
+        let limiter = RateLimiter::new(100.0, Duration::from_secs(10));
+        let start = limiter.start;
+
+        assert_eq!(limiter.rate_at(start), 1.0);
+        assert_eq!(limiter.rate_at(start + Duration::from_secs(5)), 50.0);
+        assert_eq!(limiter.rate_at(start + Duration::from_secs(20)), 100.0);
+
+        // rates below 1 operation per second aren't ramped up
+        assert_eq!(RateLimiter::new(0.5, Duration::from_secs(10)).rate_at(start), 0.5);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(RateLimit { ops_per_second: Some(100.0), ..Default::default() }.validate().is_ok());
+        assert!(RateLimit { per_connection: Some(0.0), ..Default::default() }.validate().is_err());
+        assert!(RateLimit { ops_per_second: Some(f64::NAN), ..Default::default() }.validate().is_err());
+    }
+}
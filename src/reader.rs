@@ -0,0 +1,90 @@
+//! Reads entries from previous exports, e.g. to insert or verify them.
+
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::compression;
+use crate::types::{EntryReceiver, LdapEntry};
+
+/// The task started by `start_reader_task`. It returns the number of entries read, or the error
+/// that stopped it. An error closes the receiver like the end of the file does, so the result
+/// has to be checked once the entries have been processed.
+pub type ReaderTask = JoinHandle<anyhow::Result<u64>>;
+
+/// Parses the lines of an export into entries.
+pub trait EntryParser: Send + 'static {
+    /// The name of the format, used in error messages.
+    const FORMAT: &'static str;
+
+    /// Processes the next line (without line terminator) and returns the entry it completes,
+    /// if any.
+    fn feed_line(&mut self, line: &str) -> anyhow::Result<Option<LdapEntry>>;
+
+    /// Processes the end of the file and returns the last entry, if it is still incomplete.
+    fn finish(self) -> anyhow::Result<Option<LdapEntry>>;
+}
+
+/// Starts a task reading the entries from `path` using `parser`. Compressed files are
+/// decompressed based on their extension, `-` reads from stdin. Reading stops early once the
+/// receiver is dropped.
+pub async fn start_reader_task<P: AsRef<Path>, E: EntryParser>(path: P, mut parser: E) -> anyhow::Result<(EntryReceiver, ReaderTask)> {
+    let path = path.as_ref();
+    let name = compression::display_name(path);
+    let reader = compression::open_async(path)
+        .await
+        .with_context(|| format!("failed to open {name}"))?;
+    let (tx, rx) = mpsc::channel(500_000);
+
+    let task = tokio::spawn(async move {
+        let mut lines = reader.lines();
+        let mut count = 0;
+
+        while let Some(line) = lines.next_line().await.with_context(|| format!("failed to read {name}"))? {
+            let entry = parser.feed_line(&line).with_context(|| format!("invalid {} in {name}", E::FORMAT))?;
+            if let Some(entry) = entry {
+                if tx.send(entry).await.is_err() {
+                    return Ok(count);
+                }
+                count += 1;
+            }
+        }
+
+        if let Some(entry) = parser.finish().with_context(|| format!("invalid {} in {name}", E::FORMAT))? {
+            if tx.send(entry).await.is_ok() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    });
+
+    Ok((rx, task))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jsonl::JsonlParser;
+    use crate::testing::TempPath;
+
+    #[tokio::test]
+    async fn test_reader_stops_at_error() {
+        let path = TempPath::new("reader.jsonl");
+        std::fs::write(&path, "{\"dn\": \"dc=org\", \"attributes\": {}}\n\n{\"dn\": 1}\n{\"dn\": \"dc=com\", \"attributes\": {}}\n").unwrap();
+
+        let (mut entries, task) = start_reader_task(&path, JsonlParser::default()).await.unwrap();
+        let first = entries.recv().await;
+        let rest = entries.recv().await;
+        let result = task.await.unwrap();
+
+        assert_eq!(first.unwrap().0, "dc=org");
+        // the error closes the receiver like the end of the file
+        assert!(rest.is_none());
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.starts_with("invalid JSON in ") && err.contains("line 3"), "{err}");
+    }
+}
@@ -0,0 +1,12 @@
+//! The LDAP result codes ldapfill handles specifically, see RFC 4511, section 4.1.9.
+
+/// The operation took longer than the time limit of the server.
+pub const TIME_LIMIT_EXCEEDED: u32 = 3;
+/// The entry the operation refers to, or its parent, doesn't exist.
+pub const NO_SUCH_OBJECT: u32 = 32;
+/// The server is too busy to process the operation.
+pub const BUSY: u32 = 51;
+/// The server is shutting down or a subsystem it needs is unavailable.
+pub const UNAVAILABLE: u32 = 52;
+/// The entry to add already exists.
+pub const ENTRY_ALREADY_EXISTS: u32 = 68;
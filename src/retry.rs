@@ -0,0 +1,175 @@
+//! Retries LDAP operations that failed for transient reasons.
+//!
+//! Busy or unavailable servers, exceeded time limits and dropped connections are retried with
+//! exponential backoff. Every other error, e.g. `entryAlreadyExists` or `objectClassViolation`,
+//! is permanent and returned immediately.
+
+use std::future::Future;
+use std::time::Duration;
+
+use ldap3::LdapError;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+
+use crate::result_codes::{BUSY, TIME_LIMIT_EXCEEDED, UNAVAILABLE};
+
+/// The `[ldap.retry]` section of the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct RetryPolicy {
+    /// How often a failed operation is retried, 0 disables retries.
+    pub max_retries: u32,
+    /// The delay before the first retry in milliseconds. It is doubled for every further retry.
+    pub initial_backoff: u64,
+    /// The maximum delay between two retries in milliseconds.
+    pub max_backoff: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: 100,
+            max_backoff: 10_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (starting at 0). A random jitter of up to half the
+    /// delay avoids all connections retrying at the same time.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial_backoff
+            .saturating_mul(1 << retry.min(32))
+            .min(self.max_backoff)
+            .max(1);
+        let jitter = thread_rng().gen_range(0..=delay / 2);
+
+        Duration::from_millis(delay - jitter)
+    }
+}
+
+/// LDAP result codes that indicate a temporary condition.
+const TRANSIENT_RESULT_CODES: [u32; 3] = [TIME_LIMIT_EXCEEDED, BUSY, UNAVAILABLE];
+
+/// Whether retrying the operation that failed with `error` might succeed.
+pub fn is_transient(error: &LdapError) -> bool {
+    match error {
+        LdapError::LdapResult { result } => TRANSIENT_RESULT_CODES.contains(&result.rc),
+        error => is_lost_response(error),
+    }
+}
+
+/// Whether the connection has been closed or the operation timed out before the response of the
+/// server arrived. Unlike a transient result code, the server may have performed the operation.
+fn is_lost_response(error: &LdapError) -> bool {
+    matches!(
+        error,
+        LdapError::Io { .. }
+            | LdapError::OpSend { .. }
+            | LdapError::ResultRecv { .. }
+            | LdapError::IdScrubSend { .. }
+            | LdapError::Timeout { .. }
+    )
+}
+
+/// The failed attempts of an operation run by `with_retries`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attempts {
+    /// The number of retries.
+    pub retries: u32,
+    /// Whether the response to a retried attempt got lost, so the server may have performed it.
+    pub lost_response: bool,
+}
+
+/// Runs `operation` until it succeeds, fails permanently or `policy` allows no more retries.
+/// Returns the failed attempts along with the last result.
+pub async fn with_retries<T, F, Fut>(policy: &RetryPolicy, mut operation: F) -> (Attempts, Result<T, LdapError>)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, LdapError>>,
+{
+    let mut attempts = Attempts::default();
+    loop {
+        match operation().await {
+            Err(e) if attempts.retries < policy.max_retries && is_transient(&e) => {
+                let delay = policy.backoff(attempts.retries);
+                debug!("retrying in {delay:?} after transient error: {e}");
+                tokio::time::sleep(delay).await;
+                attempts.retries += 1;
+                attempts.lost_response |= is_lost_response(&e);
+            }
+            result => return (attempts, result),
+        }
+    }
+}
+
+/// Returns the error of an operation that failed with the result code `rc`.
+#[cfg(test)]
+pub(crate) fn result_error(rc: u32) -> LdapError {
+    LdapError::LdapResult {
+        result: ldap3::LdapResult { rc, matched: String::new(), text: String::new(), refs: vec![], ctrls: vec![] },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy { max_retries: 10, initial_backoff: 100, max_backoff: 1000 };
+
+        for (retry, max) in [(0, 100), (1, 200), (2, 400), (5, 1000), (40, 1000)] {
+            let delay = policy.backoff(retry).as_millis() as u64;
+            assert!((max / 2..=max).contains(&delay), "retry {retry}: {delay}ms");
+        }
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&result_error(51)));
+        assert!(is_transient(&result_error(52)));
+        assert!(is_transient(&LdapError::Io { source: std::io::ErrorKind::ConnectionReset.into() }));
+        // entryAlreadyExists, objectClassViolation
+        assert!(!is_transient(&result_error(68)));
+        assert!(!is_transient(&result_error(65)));
+    }
+
+    #[tokio::test]
+    async fn test_with_retries() {
+        let policy = RetryPolicy { max_retries: 3, initial_backoff: 1, max_backoff: 1 };
+
+        let mut count = 0;
+        let (attempts, result) = with_retries(&policy, || {
+            count += 1;
+            let result = if count < 3 { Err(result_error(51)) } else { Ok(count) };
+            async move { result }
+        })
+        .await;
+        assert_eq!((attempts, result.unwrap()), (Attempts { retries: 2, lost_response: false }, 3));
+
+        let (attempts, result) = with_retries(&policy, || async { Err::<(), _>(result_error(51)) }).await;
+        assert_eq!(attempts.retries, 3);
+        assert!(result.is_err());
+
+        let (attempts, result) = with_retries(&policy, || async { Err::<(), _>(result_error(68)) }).await;
+        assert_eq!(attempts.retries, 0);
+        assert!(result.is_err());
+
+        let mut count = 0;
+        let (attempts, result) = with_retries(&policy, || {
+            count += 1;
+            let result = match count {
+                1 => Err(LdapError::Io { source: std::io::ErrorKind::ConnectionReset.into() }),
+                2 => Err(result_error(51)),
+                _ => Ok(()),
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(attempts, Attempts { retries: 2, lost_response: true });
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,428 @@
+//! Sinks consume generated entries. Every output format (LDIF, CSV, JSON Lines) as well as
+//! inserting into a running server is an `EntrySink`. Any number of sinks can be registered
+//! in a `SinkRegistry`, which hands every generated entry to all of them.
+//!
+//! Sinks are configured on the command line using `--output <type>:<path>` or in the
+//! configuration file:
+//!
+//! ```toml
+//! [[sinks]]
+//! type = "ldif"
+//! file = "out.ldif"
+//!
+//! [[sinks]]
+//! type = "ldif"
+//! file = "chunked.ldif.gz"
+//! split = { entries = 100000 }
+//!
+//! [[sinks]]
+//! type = "ldif-modify"
+//! file = "modify.ldif"
+//! ratio = 0.05
+//!
+//! [[sinks]]
+//! type = "csv"
+//! directory = "./csv"
+//! compress = "gzip"
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+
+use crate::compression::{self, Compression};
+use crate::credentials::{
+    default_credential_attributes, default_credential_classes, default_credential_sampling, CredentialFilter,
+};
+use crate::ldif::{changes, ChunkLimit};
+use crate::types::LdapEntry;
+
+/// The number of entries a sink has processed, per output file or other destination.
+pub type SinkSummary = Vec<(String, u64)>;
+
+/// Consumer of generated entries.
+#[async_trait]
+pub trait EntrySink: Send {
+    /// A short description of the sink, used in log and error messages.
+    fn name(&self) -> &str;
+
+    /// Whether the sink advances the progress bar on its own. If none of the registered
+    /// sinks does, the progress bar advances whenever an entry has been handed to all sinks.
+    fn reports_progress(&self) -> bool {
+        false
+    }
+
+    /// Hands `entry` to the sink. An error means the sink cannot accept any more entries.
+    async fn send(&mut self, entry: LdapEntry) -> anyhow::Result<()>;
+
+    /// Closes the sink and waits until all entries sent so far have been processed and
+    /// flushed. Returns how many entries have been written to which destination.
+    async fn close(self: Box<Self>) -> anyhow::Result<SinkSummary>;
+}
+
+/// A sink that forwards entries to a background task, e.g. one of the file exporters.
+/// Closing the sink drops the sender, which stops the task, and returns the task's result.
+pub struct ChannelSink {
+    name: String,
+    sender: UnboundedSender<LdapEntry>,
+    task: JoinHandle<anyhow::Result<SinkSummary>>,
+}
+
+impl ChannelSink {
+    pub fn new(
+        name: String,
+        sender: UnboundedSender<LdapEntry>,
+        task: JoinHandle<anyhow::Result<SinkSummary>>,
+    ) -> Self {
+        Self { name, sender, task }
+    }
+}
+
+#[async_trait]
+impl EntrySink for ChannelSink {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn send(&mut self, entry: LdapEntry) -> anyhow::Result<()> {
+        self.sender
+            .send(entry)
+            .map_err(|_| anyhow!("{} stopped unexpectedly", self.name))
+    }
+
+    async fn close(self: Box<Self>) -> anyhow::Result<SinkSummary> {
+        let ChannelSink { name, sender, task } = *self;
+        drop(sender);
+
+        task.await.with_context(|| format!("{name} panicked"))?
+    }
+}
+
+/// Describes a file sink, either from the configuration file or from the `--output` option.
+/// If `compress` is not set, the compression is derived from the file extension. CSV files
+/// are only compressed if `compress` is set.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkConfig {
+    Ldif {
+        file: String,
+        #[serde(default)]
+        compress: Option<Compression>,
+        #[serde(default)]
+        split: Option<ChunkLimit>,
+        /// Write `changetype: add` change records instead of content records.
+        #[serde(default)]
+        changes: bool,
+    },
+    /// `changetype: modify` records for a random subset (`ratio`) of the entries.
+    #[serde(rename = "ldif-modify")]
+    LdifModify {
+        file: String,
+        #[serde(default)]
+        compress: Option<Compression>,
+        #[serde(default = "default_modify_ratio")]
+        ratio: f64,
+    },
+    /// `changetype: delete` records for all entries, children first.
+    #[serde(rename = "ldif-delete")]
+    LdifDelete {
+        file: String,
+        #[serde(default)]
+        compress: Option<Compression>,
+    },
+    Csv {
+        directory: String,
+        #[serde(default)]
+        compress: Option<Compression>,
+    },
+    Jsonl {
+        file: String,
+        #[serde(default)]
+        compress: Option<Compression>,
+    },
+    /// The DN and the credential `attributes` of entries of the given `classes`, optionally
+    /// only of `every` n-th entry.
+    Credentials {
+        file: String,
+        #[serde(default)]
+        compress: Option<Compression>,
+        #[serde(default = "default_credential_classes")]
+        classes: Vec<String>,
+        #[serde(default = "default_credential_attributes")]
+        attributes: Vec<String>,
+        #[serde(default = "default_credential_sampling")]
+        every: u64,
+    },
+}
+
+impl SinkConfig {
+    /// Creates the output file(s) and starts the sink. `default_compression` is used for files
+    /// that neither specify a compression nor have a compression extension. `seed` is the seed
+    /// of the run, used by sinks generating random values themselves.
+    pub async fn start(&self, default_compression: Option<Compression>, seed: u64) -> anyhow::Result<Box<dyn EntrySink>> {
+        let sink = match self {
+            SinkConfig::Ldif { file, compress, split, changes } => {
+                let (file, compression) = resolve_compression(file, *compress, default_compression)?;
+                crate::ldif::start_ldif_export_task(file, compression, *split, *changes).await?
+            }
+            SinkConfig::LdifModify { file, compress, ratio } => {
+                let (file, compression) = resolve_compression(file, *compress, default_compression)?;
+                changes::start_modify_export_task(file, compression, *ratio, crate::cmd::get_generators(), seed).await?
+            }
+            SinkConfig::LdifDelete { file, compress } => {
+                let (file, compression) = resolve_compression(file, *compress, default_compression)?;
+                changes::start_delete_export_task(file, compression).await?
+            }
+            SinkConfig::Csv { directory, compress } => {
+                let compression = compress.or(default_compression).unwrap_or(Compression::None);
+                crate::csv::start_csv_task(directory, compression, crate::cmd::get_csv_format(), crate::cmd::get_generators()).await?
+            }
+            SinkConfig::Jsonl { file, compress } => {
+                let (file, compression) = resolve_compression(file, *compress, default_compression)?;
+                crate::jsonl::start_jsonl_export_task(file, compression).await?
+            }
+            SinkConfig::Credentials { file, compress, classes, attributes, every } => {
+                let (file, compression) = resolve_compression(file, *compress, default_compression)?;
+                let filter = CredentialFilter { classes: classes.clone(), attributes: attributes.clone(), every: *every };
+                crate::credentials::start_credentials_export_task(file, compression, filter).await?
+            }
+        };
+
+        Ok(Box::new(sink))
+    }
+
+    /// Whether the sink writes to stdout instead of a file.
+    pub fn is_stdout(&self) -> bool {
+        match self {
+            SinkConfig::Ldif { file, .. }
+            | SinkConfig::LdifModify { file, .. }
+            | SinkConfig::LdifDelete { file, .. }
+            | SinkConfig::Jsonl { file, .. }
+            | SinkConfig::Credentials { file, .. } => compression::is_stdout(Path::new(file)),
+            SinkConfig::Csv { .. } => false,
+        }
+    }
+}
+
+pub fn default_modify_ratio() -> f64 {
+    0.1
+}
+
+/// Returns the path to write to and the compression to use. An explicitly requested compression
+/// takes precedence over the file extension, which in turn takes precedence over the default.
+/// If the compression is not derived from the extension, the matching one is appended to `file`.
+/// Disabling the compression of a file with a compression extension is an error.
+fn resolve_compression(
+    file: &str,
+    compress: Option<Compression>,
+    default_compression: Option<Compression>,
+) -> anyhow::Result<(PathBuf, Compression)> {
+    let path = Path::new(file);
+    let compression = match (compress, Compression::from_path(path)) {
+        (Some(Compression::None), Compression::Gzip | Compression::Zstd) => {
+            bail!("{file} is not compressed, remove the compression extension or the `none` compression")
+        }
+        (Some(compression), _) => compression,
+        (None, Compression::None) => default_compression.unwrap_or(Compression::None),
+        (None, from_extension) => from_extension,
+    };
+
+    Ok((compression.apply_extension(path), compression))
+}
+
+impl FromStr for SinkConfig {
+    type Err = anyhow::Error;
+
+    /// Parses `<type>:<path>`, e.g. `ldif:out.ldif` or `csv:./csv`. Use `-` as path to write
+    /// to stdout.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((kind, path)) = s.split_once(':') else {
+            bail!("expected <type>:<path>, got {s}");
+        };
+
+        if path.is_empty() {
+            bail!("missing path for output {kind}");
+        }
+
+        let path = path.to_owned();
+        match kind {
+            "ldif" => Ok(SinkConfig::Ldif { file: path, compress: None, split: None, changes: false }),
+            "ldif-modify" => Ok(SinkConfig::LdifModify { file: path, compress: None, ratio: default_modify_ratio() }),
+            "ldif-delete" => Ok(SinkConfig::LdifDelete { file: path, compress: None }),
+            "csv" => Ok(SinkConfig::Csv { directory: path, compress: None }),
+            "jsonl" => Ok(SinkConfig::Jsonl { file: path, compress: None }),
+            "credentials" => Ok(SinkConfig::Credentials {
+                file: path,
+                compress: None,
+                classes: default_credential_classes(),
+                attributes: default_credential_attributes(),
+                every: default_credential_sampling(),
+            }),
+            kind => bail!("unknown output type: {kind} (expected ldif, ldif-modify, ldif-delete, csv, jsonl or credentials)"),
+        }
+    }
+}
+
+/// Holds all sinks of a run and distributes entries among them.
+#[derive(Default)]
+pub struct SinkRegistry {
+    sinks: Vec<Box<dyn EntrySink>>,
+}
+
+impl SinkRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, sink: Box<dyn EntrySink>) {
+        debug!("Registered sink: {}", sink.name());
+        self.sinks.push(sink);
+    }
+
+    /// Starts the sink described by `config` and registers it.
+    pub async fn start(&mut self, config: &SinkConfig, default_compression: Option<Compression>, seed: u64) -> anyhow::Result<()> {
+        let sink = config.start(default_compression, seed).await?;
+        self.register(sink);
+
+        Ok(())
+    }
+
+    pub fn reports_progress(&self) -> bool {
+        self.sinks.iter().any(|s| s.reports_progress())
+    }
+
+    /// Sends `entry` to all registered sinks.
+    pub async fn send(&mut self, entry: LdapEntry) -> anyhow::Result<()> {
+        let Some((last, sinks)) = self.sinks.split_last_mut() else {
+            return Ok(());
+        };
+
+        for sink in sinks {
+            sink.send(entry.clone()).await?;
+        }
+
+        last.send(entry).await
+    }
+
+    /// Closes all sinks, even if some of them fail. Returns the combined summary of all sinks
+    /// or an error if any sink failed.
+    pub async fn close(self) -> anyhow::Result<SinkSummary> {
+        let mut failed = vec![];
+        let mut summary = vec![];
+
+        for sink in self.sinks {
+            let name = sink.name().to_owned();
+            match sink.close().await {
+                Ok(s) => summary.extend(s),
+                Err(e) => {
+                    error!("{name} failed: {e:#}");
+                    failed.push(name);
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            bail!("failed to close: {}", failed.join(", "));
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ldif::ByteSize;
+
+    #[test]
+    fn parse_sink_config() {
+        assert_eq!(
+            "ldif:out.ldif".parse::<SinkConfig>().unwrap(),
+            SinkConfig::Ldif { file: "out.ldif".to_string(), compress: None, split: None, changes: false }
+        );
+        assert_eq!(
+            "csv:./csv".parse::<SinkConfig>().unwrap(),
+            SinkConfig::Csv { directory: "./csv".to_string(), compress: None }
+        );
+        assert_eq!(
+            "ldif-delete:delete.ldif".parse::<SinkConfig>().unwrap(),
+            SinkConfig::LdifDelete { file: "delete.ldif".to_string(), compress: None }
+        );
+        assert_eq!(
+            "jsonl:C:/out.jsonl".parse::<SinkConfig>().unwrap(),
+            SinkConfig::Jsonl { file: "C:/out.jsonl".to_string(), compress: None }
+        );
+    }
+
+    #[test]
+    fn sink_is_stdout() {
+        assert!("ldif:-".parse::<SinkConfig>().unwrap().is_stdout());
+        assert!("jsonl:-".parse::<SinkConfig>().unwrap().is_stdout());
+        assert!("ldif-delete:-".parse::<SinkConfig>().unwrap().is_stdout());
+        assert!(!"ldif:out.ldif".parse::<SinkConfig>().unwrap().is_stdout());
+        assert!(!"ldif:./-".parse::<SinkConfig>().unwrap().is_stdout());
+        assert!(!"csv:-".parse::<SinkConfig>().unwrap().is_stdout());
+    }
+
+    #[test]
+    fn parse_invalid_sink_config() {
+        assert!("out.ldif".parse::<SinkConfig>().is_err());
+        assert!("ldif:".parse::<SinkConfig>().is_err());
+        assert!("xml:out.xml".parse::<SinkConfig>().is_err());
+    }
+
+    #[test]
+    fn resolve_sink_compression() {
+        assert_eq!(
+            resolve_compression("out.ldif", None, Some(Compression::Gzip)).unwrap(),
+            (PathBuf::from("out.ldif.gz"), Compression::Gzip)
+        );
+        assert_eq!(
+            resolve_compression("out.ldif.zst", None, Some(Compression::Gzip)).unwrap(),
+            (PathBuf::from("out.ldif.zst"), Compression::Zstd)
+        );
+        assert_eq!(
+            resolve_compression("out.ldif", Some(Compression::None), Some(Compression::Gzip)).unwrap(),
+            (PathBuf::from("out.ldif"), Compression::None)
+        );
+        assert_eq!(
+            resolve_compression("out.ldif", None, None).unwrap(),
+            (PathBuf::from("out.ldif"), Compression::None)
+        );
+        assert!(resolve_compression("out.ldif.gz", Some(Compression::None), None).is_err());
+        assert!(resolve_compression("out.ldif.zst", Some(Compression::None), Some(Compression::Gzip)).is_err());
+    }
+
+    #[test]
+    fn deserialize_sink_config() {
+        #[derive(Deserialize)]
+        struct Sinks {
+            sinks: Vec<SinkConfig>,
+        }
+
+        let sinks: Sinks = toml::from_str(
+            "[[sinks]]\ntype = \"ldif\"\nfile = \"out.ldif\"\nsplit = { size = \"1M\" }\n\n[[sinks]]\ntype = \"csv\"\ndirectory = \"csv\"\ncompress = \"zstd\"\n\n[[sinks]]\ntype = \"credentials\"\nfile = \"users.csv\"\nevery = 100\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            sinks.sinks,
+            vec![
+                SinkConfig::Ldif { file: "out.ldif".to_string(), compress: None, split: Some(ChunkLimit::Size(ByteSize(1 << 20))), changes: false },
+                SinkConfig::Csv { directory: "csv".to_string(), compress: Some(Compression::Zstd) },
+                SinkConfig::Credentials {
+                    file: "users.csv".to_string(),
+                    compress: None,
+                    classes: vec!["inetOrgPerson".to_string()],
+                    attributes: vec!["uid".to_string(), "userPassword".to_string()],
+                    every: 100
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! Helpers shared by the tests.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A path in the temporary directory, unique to the test process. The file or directory is
+/// removed when the `TempPath` is dropped, also if the test fails.
+#[derive(Debug)]
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Returns the path for `name`, which has to be unique among the tests.
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("ldapfill-test-{}-{name}", std::process::id())))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        // the test may have failed before creating it
+        drop(if self.0.is_dir() { std::fs::remove_dir_all(&self.0) } else { std::fs::remove_file(&self.0) });
+    }
+}
@@ -2,10 +2,14 @@
 
 use std::collections::HashSet;
 
-use tokio::sync::mpsc::{UnboundedSender, UnboundedReceiver, Sender, Receiver};
+use tokio::sync::mpsc::{UnboundedReceiver, Sender, Receiver};
+
+use crate::insert::InsertOutcome;
 
 pub type LdapEntry = (String, Vec<(String, HashSet<String>)>);
 pub type EntrySender = Sender<LdapEntry>;
 pub type EntryReceiver = Receiver<LdapEntry>;
-pub type LdifSender = UnboundedSender<LdapEntry>;
 pub type LdifReceiver = UnboundedReceiver<LdapEntry>;
+/// The position of the inserted entry, the number of retries the insert took and its final result.
+pub type InsertResult = (u64, u32, Result<InsertOutcome, Box<dyn std::error::Error + Send>>);
+pub type InsertResultReceiver = UnboundedReceiver<InsertResult>;
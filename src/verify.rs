@@ -0,0 +1,242 @@
+//! Verifies that the entries on the server match a previous export.
+//!
+//! Every entry of the export is read back from the server and its attributes are compared with
+//! the exported values. Values are compared exactly, except for the object classes and the
+//! attributes in `CompareOptions::ignore_case`, whose case and insignificant spaces are ignored
+//! like their matching rules do. Object classes only have to be present on the server, as
+//! servers usually add the superclasses.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use ldap3::{LdapError, Scope, SearchEntry};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+
+use crate::ldap_pool::LdapPool;
+use crate::progress::{ProgressMessage, ProgressSender};
+use crate::result_codes::NO_SUCH_OBJECT;
+use crate::retry::{with_retries, RetryPolicy};
+use crate::types::{EntryReceiver, LdapEntry};
+
+/// The number of entries per result of the verification.
+#[derive(Debug, Default)]
+pub struct VerifyCounts {
+    pub matching: u64,
+    pub missing: u64,
+    pub differing: u64,
+    pub failed: u64,
+}
+
+/// Which attributes are compared, and how.
+#[derive(Debug, Default)]
+pub struct CompareOptions {
+    /// Attributes that are not compared.
+    pub ignored: Vec<String>,
+    /// Attributes whose values are compared ignoring case and insignificant spaces, in addition
+    /// to the object classes.
+    pub ignore_case: Vec<String>,
+}
+
+/// Reads back the received entries, using one task per connection of `pool`, and compares
+/// them with the server. Missing and differing entries are printed above the progress bar.
+pub async fn verify_entries(
+    pool: Arc<LdapPool>,
+    entries: EntryReceiver,
+    retry: RetryPolicy,
+    options: CompareOptions,
+    progress: ProgressSender,
+) -> VerifyCounts {
+    let entries = Arc::new(Mutex::new(entries));
+    let options = Arc::new(options);
+
+    let mut workers = JoinSet::new();
+    for _ in 0..pool.connections() {
+        let (pool, entries, options, progress) = (pool.clone(), entries.clone(), options.clone(), progress.clone());
+        workers.spawn(async move {
+            let mut counts = VerifyCounts::default();
+
+            loop {
+                // the lock is released before the entry is read from the server
+                let Some(entry) = entries.lock().await.recv().await else { break };
+
+                let message = match read_entry(&pool, &entry, &retry).await {
+                    Ok(Some(actual)) => {
+                        let differences = compare(&entry.1, &actual, &options);
+                        if differences.is_empty() {
+                            counts.matching += 1;
+                            ProgressMessage::Progress
+                        } else {
+                            counts.differing += 1;
+                            ProgressMessage::ProgressWithMessage(format!("Differs: {}: {}", entry.0, differences.join(", ")))
+                        }
+                    }
+                    Ok(None) => {
+                        counts.missing += 1;
+                        ProgressMessage::ProgressWithMessage(format!("Missing: {}", entry.0))
+                    }
+                    Err(e) => {
+                        counts.failed += 1;
+                        ProgressMessage::ProgressWithMessage(format!("Error reading {}: {e}", entry.0))
+                    }
+                };
+                drop(progress.send(message));
+            }
+
+            counts
+        });
+    }
+
+    let mut counts = VerifyCounts::default();
+    while let Some(worker) = workers.join_next().await {
+        match worker {
+            Ok(worker_counts) => {
+                counts.matching += worker_counts.matching;
+                counts.missing += worker_counts.missing;
+                counts.differing += worker_counts.differing;
+                counts.failed += worker_counts.failed;
+            }
+            Err(e) => error!("verify task failed: {e}"),
+        }
+    }
+
+    counts
+}
+
+/// Reads the attributes of `entry` from the server. Returns `None` if the entry doesn't exist.
+async fn read_entry(pool: &LdapPool, entry: &LdapEntry, retry: &RetryPolicy) -> Result<Option<HashMap<String, Vec<String>>>, LdapError> {
+    let (dn, attributes) = entry;
+    let names: Vec<&str> = attributes.iter().map(|(name, _)| name.as_str()).collect();
+
+    let (_, result) = with_retries(retry, || {
+        let mut conn = pool.get_conn();
+        let names = names.clone();
+        async move { conn.search(dn, Scope::Base, "(objectClass=*)", names).await?.success() }
+    })
+    .await;
+
+    match result {
+        Ok((entries, _)) => Ok(entries.into_iter().next().map(|entry| {
+            let SearchEntry { mut attrs, bin_attrs, .. } = SearchEntry::construct(entry);
+            // values that aren't valid UTF-8 can't match the exported strings anyway
+            for (name, values) in bin_attrs {
+                let values = values.iter().map(|v| String::from_utf8_lossy(v).into_owned());
+                attrs.entry(name).or_default().extend(values);
+            }
+            attrs
+        })),
+        Err(LdapError::LdapResult { result }) if result.rc == NO_SUCH_OBJECT => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compares the `expected` attributes with the `actual` ones of the server. Returns a
+/// description of every differing attribute.
+fn compare(expected: &[(String, HashSet<String>)], actual: &HashMap<String, Vec<String>>, options: &CompareOptions) -> Vec<String> {
+    let mut differences = vec![];
+
+    for (name, values) in expected {
+        if options.ignored.iter().any(|i| i.eq_ignore_ascii_case(name)) {
+            continue;
+        }
+
+        let found: Vec<&String> = actual
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, values)| values.iter())
+            .collect();
+        let object_class = name.eq_ignore_ascii_case("objectclass");
+        let ignore_case = object_class || options.ignore_case.iter().any(|a| a.eq_ignore_ascii_case(name));
+        let expected_values: HashSet<String> = values.iter().map(|v| normalize(v, ignore_case)).collect();
+        let actual_values: HashSet<String> = found.iter().map(|v| normalize(v, ignore_case)).collect();
+
+        let matches = if object_class {
+            expected_values.is_subset(&actual_values)
+        } else {
+            expected_values == actual_values
+        };
+
+        if !matches {
+            differences.push(format!("{name} expected {:?}, found {:?}", sorted(values.iter()), sorted(found.into_iter())));
+        }
+    }
+
+    differences
+}
+
+/// Returns `value` as it is compared: unchanged, or lowercased without leading, trailing and
+/// repeated spaces if `ignore_case` is set.
+fn normalize(value: &str, ignore_case: bool) -> String {
+    if ignore_case {
+        value.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+    } else {
+        value.to_owned()
+    }
+}
+
+fn sorted<'a>(values: impl Iterator<Item = &'a String>) -> Vec<&'a str> {
+    let mut values: Vec<&str> = values.map(String::as_str).collect();
+    values.sort_unstable();
+    values
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values(v: &[&str]) -> HashSet<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_compare() {
+        let expected = vec![
+            ("objectClass".to_string(), values(&["inetOrgPerson"])),
+            ("cn".to_string(), values(&["Jane  Doe"])),
+            ("mail".to_string(), values(&["jane@example.org", "jdoe@example.org"])),
+            ("userPassword".to_string(), values(&["secret"])),
+        ];
+        let actual = HashMap::from([
+            ("objectClass".to_string(), vec!["top".to_string(), "person".to_string(), "inetorgperson".to_string()]),
+            ("CN".to_string(), vec!["Jane  Doe".to_string()]),
+            ("mail".to_string(), vec!["jdoe@example.org".to_string(), "jane@example.org".to_string()]),
+            ("userPassword".to_string(), vec!["{SSHA}abcdef".to_string()]),
+        ]);
+
+        let options = CompareOptions { ignored: vec!["userpassword".to_string()], ..Default::default() };
+        assert!(compare(&expected, &actual, &options).is_empty());
+
+        let differences = compare(&expected, &actual, &CompareOptions::default());
+        assert_eq!(differences, ["userPassword expected [\"secret\"], found [\"{SSHA}abcdef\"]"]);
+
+        let mut missing = actual.clone();
+        missing.remove("mail");
+        missing.insert("objectClass".to_string(), vec!["person".to_string()]);
+        let differences = compare(&expected, &missing, &options);
+        assert_eq!(differences.len(), 2);
+        assert!(differences[1].starts_with("mail expected"), "{differences:?}");
+    }
+
+    #[test]
+    fn test_compare_case() {
+        let expected = vec![
+            ("cn".to_string(), values(&["Jane  Doe"])),
+            ("mail".to_string(), values(&["jane@example.org"])),
+        ];
+        let actual = HashMap::from([
+            ("cn".to_string(), vec!["jane doe ".to_string()]),
+            ("mail".to_string(), vec!["Jane@Example.org".to_string()]),
+        ]);
+
+        // values differing only in case don't match by default
+        let differences = compare(&expected, &actual, &CompareOptions::default());
+        assert_eq!(differences.len(), 2, "{differences:?}");
+
+        let options = CompareOptions { ignore_case: vec!["CN".to_string()], ..Default::default() };
+        let differences = compare(&expected, &actual, &options);
+        assert_eq!(differences, ["mail expected [\"jane@example.org\"], found [\"Jane@Example.org\"]"]);
+
+        let options = CompareOptions { ignore_case: vec!["cn".to_string(), "mail".to_string()], ..Default::default() };
+        assert!(compare(&expected, &actual, &options).is_empty());
+    }
+}